@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// A single place classes are looked up in.
+pub enum ClassPathEntry{
+    Dir(PathBuf),
+    Jar(ZipArchive<File>)
+}
+
+impl ClassPathEntry{
+    /// Opens `path` as a directory or, if it is a file, as a `.jar`/`.zip` archive.
+    pub fn open(path: &Path) -> io::Result<Self>{
+        if path.is_dir(){
+            return Ok(ClassPathEntry::Dir(path.to_path_buf()));
+        }
+        let archive = ZipArchive::new(File::open(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        return Ok(ClassPathEntry::Jar(archive));
+    }
+
+    /// Reads `file`, a `/`-separated path relative to the entry, if it exists.
+    pub fn read(&mut self, file: &str) -> Option<Vec<u8>>{
+        return match self {
+            ClassPathEntry::Dir(dir) => std::fs::read(dir.join(file)).ok(),
+            ClassPathEntry::Jar(archive) => {
+                let mut entry = archive.by_name(file).ok()?;
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data).ok()?;
+                Some(data)
+            }
+        }
+    }
+}
+
+/// An ordered list of directories and archives searched for class files.
+#[derive(Default)]
+pub struct ClassPath{
+    pub entries: Vec<ClassPathEntry>
+}
+
+impl ClassPath{
+    pub const fn new() -> Self{
+        ClassPath{entries: Vec::new()}
+    }
+
+    /// Builds a class path from a platform separated list such as `lib/a.jar:classes`.
+    /// Like `java`, entries that don't exist are ignored.
+    pub fn parse(paths: &str) -> io::Result<Self>{
+        let mut cp = ClassPath::new();
+        for path in std::env::split_paths(paths){
+            if !path.exists(){
+                continue;
+            }
+            cp.add(&path)?;
+        }
+        return Ok(cp);
+    }
+
+    pub fn add(&mut self, path: &Path) -> io::Result<()>{
+        self.entries.push(ClassPathEntry::open(path)?);
+        return Ok(());
+    }
+
+    /// Returns the bytes of the class file for `name` (e.g. `java/lang/Object`) from the first entry containing it.
+    pub fn find(&mut self, name: &str) -> Option<Vec<u8>>{
+        let file = name.replace('.', "/") + ".class";
+        return self.entries.iter_mut().find_map(|entry| entry.read(&file));
+    }
+}
@@ -0,0 +1,153 @@
+//! Field and method descriptors (JVMS 4.3), e.g. `I`, `[Ljava/lang/String;` or `(JLjava/lang/Object;)V`.
+
+/// The type of a field, parameter or return value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldType{
+    Byte,
+    Char,
+    Double,
+    Float,
+    Int,
+    Long,
+    Short,
+    Boolean,
+    /// Binary name of the class, e.g. `java/lang/String`.
+    Object(String),
+    Array(Box<FieldType>)
+}
+
+impl FieldType{
+    /// Parses a complete field descriptor.
+    pub fn parse(desc: &str) -> Option<FieldType>{
+        let (typ, rest) = FieldType::parse_prefix(desc)?;
+        return if rest.is_empty() { Some(typ) } else { None };
+    }
+
+    /// Parses the field descriptor at the start of `desc`, returning it and the remaining input.
+    fn parse_prefix(desc: &str) -> Option<(FieldType, &str)>{
+        let first = desc.chars().next()?;
+        let rest = &desc[first.len_utf8()..];
+        let typ = match first {
+            'B' => FieldType::Byte,
+            'C' => FieldType::Char,
+            'D' => FieldType::Double,
+            'F' => FieldType::Float,
+            'I' => FieldType::Int,
+            'J' => FieldType::Long,
+            'S' => FieldType::Short,
+            'Z' => FieldType::Boolean,
+            'L' => {
+                let end = rest.find(';')?;
+                if end == 0{
+                    return None;
+                }
+                return Some((FieldType::Object(rest[..end].to_string()), &rest[end + 1..]));
+            },
+            '[' => {
+                let (component, rest) = FieldType::parse_prefix(rest)?;
+                return Some((FieldType::Array(Box::new(component)), rest));
+            },
+            _ => return None
+        };
+        return Some((typ, rest));
+    }
+
+    /// Returns the descriptor of this type, e.g. `[Ljava/lang/String;`.
+    pub fn descriptor(&self) -> String{
+        return match self {
+            FieldType::Byte => "B".to_string(),
+            FieldType::Char => "C".to_string(),
+            FieldType::Double => "D".to_string(),
+            FieldType::Float => "F".to_string(),
+            FieldType::Int => "I".to_string(),
+            FieldType::Long => "J".to_string(),
+            FieldType::Short => "S".to_string(),
+            FieldType::Boolean => "Z".to_string(),
+            FieldType::Object(name) => format!("L{};", name),
+            FieldType::Array(component) => format!("[{}", component.descriptor())
+        }
+    }
+
+    /// Number of local variable slots a value of this type occupies.
+    pub fn slots(&self) -> usize{
+        return match self {
+            FieldType::Long | FieldType::Double => 2,
+            _ => 1
+        }
+    }
+}
+
+/// The parameter and return types of a method.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodDescriptor{
+    pub params: Vec<FieldType>,
+    /// `None` for `void` methods.
+    pub ret: Option<FieldType>
+}
+
+impl MethodDescriptor{
+    /// Parses a method descriptor such as `(IJ)Ljava/lang/String;`.
+    pub fn parse(desc: &str) -> Option<MethodDescriptor>{
+        let mut rest = desc.strip_prefix('(')?;
+        let mut params = Vec::new();
+        while !rest.starts_with(')'){
+            let (param, next) = FieldType::parse_prefix(rest)?;
+            params.push(param);
+            rest = next;
+        }
+        let ret = match &rest[1..] {
+            "V" => None,
+            ret => Some(FieldType::parse(ret)?)
+        };
+        return Some(MethodDescriptor{params, ret});
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn object(name: &str) -> FieldType{
+        return FieldType::Object(name.to_string());
+    }
+
+    #[test]
+    fn field_types(){
+        assert_eq!(FieldType::parse("I"), Some(FieldType::Int));
+        assert_eq!(FieldType::parse("J"), Some(FieldType::Long));
+        assert_eq!(FieldType::parse("Ljava/lang/String;"), Some(object("java/lang/String")));
+        assert_eq!(FieldType::parse("[[D"), Some(FieldType::Array(Box::new(FieldType::Array(Box::new(FieldType::Double))))));
+        assert_eq!(FieldType::parse("[Ljava/lang/Object;"), Some(FieldType::Array(Box::new(object("java/lang/Object")))));
+    }
+
+    #[test]
+    fn invalid_field_types(){
+        for desc in ["", "V", "X", "L;", "Ljava/lang/String", "II", "[", "é", "Lé"]{
+            assert_eq!(FieldType::parse(desc), None, "{}", desc);
+        }
+    }
+
+    #[test]
+    fn descriptor_round_trip(){
+        for desc in ["Z", "C", "[B", "Ljava/lang/String;", "[[Ljava/util/Comparator;"]{
+            assert_eq!(FieldType::parse(desc).unwrap().descriptor(), desc);
+        }
+    }
+
+    #[test]
+    fn method_descriptors(){
+        assert_eq!(MethodDescriptor::parse("()V"), Some(MethodDescriptor{params: vec![], ret: None}));
+        assert_eq!(MethodDescriptor::parse("(IJLjava/lang/Object;[S)Ljava/lang/String;"), Some(MethodDescriptor{
+            params: vec![FieldType::Int, FieldType::Long, object("java/lang/Object"), FieldType::Array(Box::new(FieldType::Short))],
+            ret: Some(object("java/lang/String"))
+        }));
+        assert_eq!(MethodDescriptor::parse("(Lé;)D").unwrap().params, vec![object("é")]);
+    }
+
+    #[test]
+    fn invalid_method_descriptors(){
+        for desc in ["", "V", "(", "(I", "()", "()VV", "(V)V", "(é)V", "()é", "(I)Lé"]{
+            assert_eq!(MethodDescriptor::parse(desc), None, "{}", desc);
+        }
+    }
+}
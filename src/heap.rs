@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use crate::Class;
+use crate::types::{ArrayTypes, Types};
+
+/// Default maximum number of bytes the heap may hold before allocations fail.
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Approximate size of an object or array header, in bytes.
+const HEADER_SIZE: usize = 16;
+
+/// An instance living on the heap: the name of its runtime class and one slot
+/// per instance field, laid out as described by `Class::layout`.
+#[derive(Debug, Clone)]
+pub struct Object{
+    pub class: String,
+    pub fields: Vec<Types>
+}
+
+/// The element type of an array: either a primitive or the name of a class
+/// (which is itself an array descriptor for nested arrays).
+#[derive(Debug, Clone)]
+pub enum ElementType{
+    Primitive(ArrayTypes),
+    Class(String)
+}
+
+impl ElementType{
+    /// Returns the element type described by the field descriptor `desc`, e.g. `I`,
+    /// `Ljava/lang/String;` or `[J`.
+    pub fn from_descriptor(desc: &str) -> ElementType{
+        return match desc {
+            "Z" => ElementType::Primitive(ArrayTypes::BOOLEAN),
+            "C" => ElementType::Primitive(ArrayTypes::CHAR),
+            "F" => ElementType::Primitive(ArrayTypes::FLOAT),
+            "D" => ElementType::Primitive(ArrayTypes::DOUBLE),
+            "B" => ElementType::Primitive(ArrayTypes::BYTE),
+            "S" => ElementType::Primitive(ArrayTypes::SHORT),
+            "I" => ElementType::Primitive(ArrayTypes::INT),
+            "J" => ElementType::Primitive(ArrayTypes::LONG),
+            _ => ElementType::Class(desc.strip_prefix('L').and_then(|d| d.strip_suffix(';')).unwrap_or(desc).to_string())
+        }
+    }
+
+    /// Returns the field descriptor of this type.
+    pub fn descriptor(&self) -> String{
+        return match self {
+            ElementType::Primitive(ArrayTypes::BOOLEAN) => "Z".to_string(),
+            ElementType::Primitive(ArrayTypes::CHAR) => "C".to_string(),
+            ElementType::Primitive(ArrayTypes::FLOAT) => "F".to_string(),
+            ElementType::Primitive(ArrayTypes::DOUBLE) => "D".to_string(),
+            ElementType::Primitive(ArrayTypes::BYTE) => "B".to_string(),
+            ElementType::Primitive(ArrayTypes::SHORT) => "S".to_string(),
+            ElementType::Primitive(ArrayTypes::INT) => "I".to_string(),
+            ElementType::Primitive(ArrayTypes::LONG) => "J".to_string(),
+            ElementType::Class(name) if name.starts_with('[') => name.clone(),
+            ElementType::Class(name) => format!("L{};", name)
+        }
+    }
+
+    pub fn default_value(&self) -> Types{
+        return match self {
+            ElementType::Primitive(ArrayTypes::LONG) => Types::Long(0),
+            ElementType::Primitive(ArrayTypes::FLOAT) => Types::Float(0.0),
+            ElementType::Primitive(ArrayTypes::DOUBLE) => Types::Double(0.0),
+            ElementType::Primitive(_) => Types::Int(0),
+            ElementType::Class(_) => Types::Null
+        }
+    }
+
+    /// Size in bytes of a single element.
+    pub fn size(&self) -> usize{
+        return match self {
+            ElementType::Primitive(ArrayTypes::BOOLEAN) | ElementType::Primitive(ArrayTypes::BYTE) => 1,
+            ElementType::Primitive(ArrayTypes::CHAR) | ElementType::Primitive(ArrayTypes::SHORT) => 2,
+            ElementType::Primitive(ArrayTypes::INT) | ElementType::Primitive(ArrayTypes::FLOAT) => 4,
+            _ => 8
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Array{
+    pub typ: ElementType,
+    pub data: Vec<Types>
+}
+
+impl Object{
+    fn size(&self) -> usize{
+        return HEADER_SIZE + 8 * self.fields.len();
+    }
+}
+
+impl Array{
+    fn size(&self) -> usize{
+        return HEADER_SIZE + self.typ.size() * self.data.len();
+    }
+}
+
+/// Objects and arrays live in separate slot tables; freed slots are reused by
+/// later allocations, so a reference stays valid for as long as it is reachable.
+pub struct Heap{
+    pub objects: Vec<Option<Object>>,
+    pub arrays: Vec<Option<Array>>,
+    free_objects: Vec<usize>,
+    free_arrays: Vec<usize>,
+    /// Interned string literals, by UTF-16 contents, mapped to their `java/lang/String` instance.
+    pub strings: BTreeMap<Vec<u16>, usize>,
+    /// Bytes currently in use.
+    pub used: usize,
+    /// Maximum number of bytes in use before an allocation fails.
+    pub limit: usize
+}
+
+impl Heap{
+    pub const fn new() -> Self{
+        Heap{
+            objects: Vec::new(),
+            arrays: Vec::new(),
+            free_objects: Vec::new(),
+            free_arrays: Vec::new(),
+            strings: BTreeMap::new(),
+            used: 0,
+            limit: DEFAULT_HEAP_LIMIT
+        }
+    }
+
+    /// Returns whether `size` more bytes can be allocated without a collection.
+    pub fn fits(&self, size: usize) -> bool{
+        return self.used + size <= self.limit;
+    }
+
+    /// Allocates a new instance of `class` with every field set to its default value
+    /// and returns its reference, or `None` if the heap limit would be exceeded.
+    pub fn new_object(&mut self, class: &Class) -> Option<usize>{
+        let fields = class.layout.iter().map(|slot| Types::default_for(&slot.desc)).collect();
+        let obj = Object{class: class.name.clone(), fields};
+        if !self.fits(obj.size()){
+            return None;
+        }
+        self.used += obj.size();
+        return Some(match self.free_objects.pop() {
+            Some(r) => { self.objects[r] = Some(obj); r },
+            None => { self.objects.push(Some(obj)); self.objects.len() - 1 }
+        });
+    }
+
+    /// Whether `value` is a primitive or a reference to a live object or array.
+    pub fn is_live(&self, value: &Types) -> bool{
+        return match value {
+            Types::Ref(r) => matches!(self.objects.get(*r), Some(Some(_))),
+            Types::Array(r) => matches!(self.arrays.get(*r), Some(Some(_))),
+            _ => true
+        }
+    }
+
+    pub fn get(&self, r: usize) -> &Object{
+        return self.objects[r].as_ref().expect("Dangling object reference");
+    }
+
+    pub fn get_mut(&mut self, r: usize) -> &mut Object{
+        return self.objects[r].as_mut().expect("Dangling object reference");
+    }
+
+    /// Allocates a new array of `len` elements of type `typ`, each set to the default value,
+    /// or returns `None` if the heap limit would be exceeded.
+    pub fn new_array(&mut self, typ: ElementType, len: usize) -> Option<usize>{
+        let size = HEADER_SIZE + typ.size() * len;
+        if !self.fits(size){
+            return None;
+        }
+        let data = vec![typ.default_value(); len];
+        self.used += size;
+        return Some(match self.free_arrays.pop() {
+            Some(r) => { self.arrays[r] = Some(Array{typ, data}); r },
+            None => { self.arrays.push(Some(Array{typ, data})); self.arrays.len() - 1 }
+        });
+    }
+
+    pub fn array(&self, r: usize) -> &Array{
+        return self.arrays[r].as_ref().expect("Dangling array reference");
+    }
+
+    pub fn array_mut(&mut self, r: usize) -> &mut Array{
+        return self.arrays[r].as_mut().expect("Dangling array reference");
+    }
+
+    /// Allocates a `java/lang/String` instance holding the UTF-16 code units `chars`.
+    /// `string_class` must be the loaded `java/lang/String` class.
+    pub fn new_string(&mut self, string_class: &Class, chars: &[u16]) -> Option<usize>{
+        let value = self.new_array(ElementType::Primitive(ArrayTypes::CHAR), chars.len())?;
+        for (i, c) in chars.iter().enumerate(){
+            self.array_mut(value).data[i] = Types::Int(*c as i32);
+        }
+        let r = match self.new_object(string_class) {
+            Some(r) => r,
+            None => {
+                // Nothing references the character array yet.
+                self.free_array(value);
+                return None;
+            }
+        };
+        let slot = string_class.field_slot("value", "[C").expect("java/lang/String has no value field");
+        self.get_mut(r).fields[slot] = Types::Array(value);
+        return Some(r);
+    }
+
+    /// Returns the interned `java/lang/String` instance for `chars`, creating it if needed.
+    pub fn intern(&mut self, string_class: &Class, chars: &[u16]) -> Option<usize>{
+        if let Some(r) = self.strings.get(chars){
+            return Some(*r);
+        }
+        let r = self.new_string(string_class, chars)?;
+        self.strings.insert(chars.to_vec(), r);
+        return Some(r);
+    }
+
+    fn free_object(&mut self, r: usize){
+        if let Some(obj) = self.objects[r].take(){
+            self.used -= obj.size();
+            self.free_objects.push(r);
+        }
+    }
+
+    fn free_array(&mut self, r: usize){
+        if let Some(arr) = self.arrays[r].take(){
+            self.used -= arr.size();
+            self.free_arrays.push(r);
+        }
+    }
+
+    /// Marks everything reachable from `roots` and the interned strings, then frees the rest.
+    /// Returns the number of bytes reclaimed.
+    pub fn collect<'a>(&mut self, roots: impl Iterator<Item=&'a Types>) -> usize{
+        let before = self.used;
+        let mut marked_objects = vec![false; self.objects.len()];
+        let mut marked_arrays = vec![false; self.arrays.len()];
+
+        let mut pending: Vec<Types> = roots.cloned().collect();
+        pending.extend(self.strings.values().map(|r| Types::Ref(*r)));
+
+        while let Some(val) = pending.pop(){
+            match val{
+                Types::Ref(r) if !marked_objects[r] => {
+                    marked_objects[r] = true;
+                    pending.extend(self.get(r).fields.iter().cloned());
+                },
+                Types::Array(r) if !marked_arrays[r] => {
+                    marked_arrays[r] = true;
+                    let arr = self.array(r);
+                    if let ElementType::Class(_) = arr.typ{
+                        pending.extend(arr.data.iter().cloned());
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        for (r, marked) in marked_objects.into_iter().enumerate(){
+            if !marked{
+                self.free_object(r);
+            }
+        }
+        for (r, marked) in marked_arrays.into_iter().enumerate(){
+            if !marked{
+                self.free_array(r);
+            }
+        }
+        return before - self.used;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::types::{ConstPool, FieldSlot};
+
+    /// A class whose instances have one reference field per name in `fields`.
+    fn class(name: &str, fields: &[&str]) -> Class{
+        let layout = fields.iter().map(|f| FieldSlot{class: name.to_string(), name: f.to_string(), desc: "Ljava/lang/Object;".to_string()}).collect();
+        return Class{
+            cp: ConstPool{consts: Vec::new()},
+            name: name.to_string(),
+            supr: "java/lang/Object".to_string(),
+            flags: 0,
+            interfaces: Vec::new(),
+            fields: Vec::new(),
+            methods: Vec::new(),
+            attributes: Vec::new(),
+            source_file: None,
+            version: [0, 52],
+            layout
+        };
+    }
+
+    fn string_class() -> Class{
+        let mut string = class("java/lang/String", &[]);
+        string.layout.push(FieldSlot{class: string.name.clone(), name: "value".to_string(), desc: "[C".to_string()});
+        return string;
+    }
+
+    #[test]
+    fn reachable_through_fields(){
+        let node = class("Node", &["next"]);
+        let mut heap = Heap::new();
+        let a = heap.new_object(&node).unwrap();
+        let b = heap.new_object(&node).unwrap();
+        let garbage = heap.new_object(&node).unwrap();
+        heap.get_mut(a).fields[0] = Types::Ref(b);
+        // A cycle that nothing else references.
+        heap.get_mut(garbage).fields[0] = Types::Ref(garbage);
+
+        let freed = heap.collect([Types::Ref(a)].iter());
+        assert_eq!(freed, HEADER_SIZE + 8);
+        assert!(heap.is_live(&Types::Ref(a)));
+        assert!(heap.is_live(&Types::Ref(b)));
+        assert!(!heap.is_live(&Types::Ref(garbage)));
+    }
+
+    #[test]
+    fn reachable_through_array_elements(){
+        let node = class("Node", &[]);
+        let mut heap = Heap::new();
+        let objects = heap.new_array(ElementType::Class("Node".to_string()), 2).unwrap();
+        let inner = heap.new_array(ElementType::Primitive(ArrayTypes::INT), 3).unwrap();
+        let outer = heap.new_array(ElementType::Class("[I".to_string()), 1).unwrap();
+        let element = heap.new_object(&node).unwrap();
+        heap.array_mut(objects).data[1] = Types::Ref(element);
+        heap.array_mut(outer).data[0] = Types::Array(inner);
+
+        heap.collect([Types::Array(objects), Types::Array(outer)].iter());
+        assert!(heap.is_live(&Types::Ref(element)));
+        assert!(heap.is_live(&Types::Array(inner)));
+
+        heap.collect([Types::Array(outer)].iter());
+        assert!(!heap.is_live(&Types::Array(objects)));
+        assert!(!heap.is_live(&Types::Ref(element)));
+        assert!(heap.is_live(&Types::Array(inner)));
+    }
+
+    #[test]
+    fn primitive_elements_are_not_references(){
+        let node = class("Node", &[]);
+        let mut heap = Heap::new();
+        let object = heap.new_object(&node).unwrap();
+        let ints = heap.new_array(ElementType::Primitive(ArrayTypes::INT), 1).unwrap();
+        heap.array_mut(ints).data[0] = Types::Int(object as i32);
+
+        heap.collect([Types::Array(ints)].iter());
+        assert!(!heap.is_live(&Types::Ref(object)));
+    }
+
+    #[test]
+    fn freed_slots_are_reused(){
+        let node = class("Node", &["next"]);
+        let mut heap = Heap::new();
+        let kept = heap.new_object(&node).unwrap();
+        let freed = heap.new_object(&node).unwrap();
+        let array = heap.new_array(ElementType::Primitive(ArrayTypes::LONG), 4).unwrap();
+        heap.collect([Types::Ref(kept)].iter());
+        assert_eq!(heap.used, HEADER_SIZE + 8);
+
+        assert_eq!(heap.new_object(&node), Some(freed));
+        assert_eq!(heap.new_array(ElementType::Primitive(ArrayTypes::BYTE), 1), Some(array));
+        assert_eq!(heap.new_object(&node), Some(2));
+        assert_eq!(heap.objects.len(), 3);
+    }
+
+    #[test]
+    fn allocation_respects_the_limit(){
+        let mut heap = Heap::new();
+        heap.limit = HEADER_SIZE + 40;
+        assert!(heap.new_array(ElementType::Primitive(ArrayTypes::INT), 11).is_none());
+        assert!(heap.new_array(ElementType::Primitive(ArrayTypes::INT), 10).is_some());
+        assert!(heap.new_array(ElementType::Primitive(ArrayTypes::BYTE), 0).is_none());
+        assert_eq!(heap.used, heap.limit);
+    }
+
+    #[test]
+    fn strings(){
+        let string = string_class();
+        let mut heap = Heap::new();
+        let chars: Vec<u16> = "hé\u{1F600}".encode_utf16().collect();
+        let r = heap.new_string(&string, &chars).unwrap();
+        let Types::Array(value) = heap.get(r).fields[0] else { panic!("String without a value") };
+        let units: Vec<Types> = chars.iter().map(|c| Types::Int(*c as i32)).collect();
+        assert_eq!(format!("{:?}", heap.array(value).data), format!("{:?}", units));
+    }
+
+    #[test]
+    fn new_string_failing_partway(){
+        let string = string_class();
+        let mut heap = Heap::new();
+        // Room for the character array, but not for the string object as well.
+        heap.limit = HEADER_SIZE + 2 * 3;
+        assert_eq!(heap.new_string(&string, &[97, 98, 99]), None);
+        assert_eq!(heap.used, 0);
+        assert!(heap.arrays.iter().all(Option::is_none));
+        assert!(heap.objects.is_empty());
+
+        heap.limit = 2 * HEADER_SIZE + 2 * 3 + 8;
+        let r = heap.new_string(&string, &[97, 98, 99]).unwrap();
+        assert!(heap.is_live(&heap.get(r).fields[0]));
+    }
+
+    #[test]
+    fn interned_strings_are_roots(){
+        let string = string_class();
+        let mut heap = Heap::new();
+        let interned = heap.intern(&string, &[120]).unwrap();
+        let plain = heap.new_string(&string, &[120]).unwrap();
+        assert_eq!(heap.intern(&string, &[120]), Some(interned));
+
+        heap.collect(std::iter::empty());
+        assert!(heap.is_live(&Types::Ref(interned)));
+        assert!(heap.is_live(&heap.get(interned).fields[0]));
+        assert!(!heap.is_live(&Types::Ref(plain)));
+    }
+}
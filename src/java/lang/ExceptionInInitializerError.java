@@ -0,0 +1,11 @@
+package java.lang;
+
+public class ExceptionInInitializerError extends LinkageError{
+    public ExceptionInInitializerError(){
+        super();
+    }
+
+    public ExceptionInInitializerError(String message){
+        super(message);
+    }
+}
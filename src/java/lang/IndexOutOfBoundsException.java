@@ -1,4 +1,11 @@
 package java.lang;
 
-public class IndexOutOfBoundsException extends Throwable{
+public class IndexOutOfBoundsException extends RuntimeException{
+    public IndexOutOfBoundsException(){
+        super();
+    }
+
+    public IndexOutOfBoundsException(String message){
+        super(message);
+    }
 }
@@ -1,4 +1,11 @@
 package java.lang;
 
-public class NullPointerException extends Throwable{
+public class NullPointerException extends RuntimeException{
+    public NullPointerException(){
+        super();
+    }
+
+    public NullPointerException(String message){
+        super(message);
+    }
 }
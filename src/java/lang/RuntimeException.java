@@ -1,4 +1,11 @@
 package java.lang;
 
-public class RuntimeException extends Throwable{
+public class RuntimeException extends Exception{
+    public RuntimeException(){
+        super();
+    }
+
+    public RuntimeException(String message){
+        super(message);
+    }
 }
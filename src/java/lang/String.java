@@ -19,6 +19,10 @@ public class String {
         return value.length;
     }
 
+    public char charAt(int index){
+        return value[index];
+    }
+
     public boolean isEmpty(){
         return value.length == 0;
     }
@@ -13,5 +13,9 @@ public class System {
 
     public final static PrintStream out = new PrintStream(new FileOutputStream(FileDescriptor.out));
 
+    public static native String getProperty(String key);
+
+    public static native void exit(int status);
+
 
 }
@@ -2,16 +2,23 @@ package java.lang;
 
 public class Throwable {
     private String message;
+    private StackTraceElement[] stackTrace;
 
     public Throwable(){
         message = null;
+        fillInStackTrace();
     }
 
     public Throwable(String details){
         message = details;
+        fillInStackTrace();
     }
 
     public String getMessage(){
         return message;
     }
+
+    public native Throwable fillInStackTrace();
+
+    public native StackTraceElement[] getStackTrace();
 }
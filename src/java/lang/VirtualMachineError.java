@@ -0,0 +1,11 @@
+package java.lang;
+
+public class VirtualMachineError extends Error{
+    public VirtualMachineError(){
+        super();
+    }
+
+    public VirtualMachineError(String message){
+        super(message);
+    }
+}
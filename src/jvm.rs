@@ -0,0 +1,824 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use crate::{Activation, Class, Thrown, natives};
+use crate::classpath::ClassPath;
+use crate::heap::{ElementType, Heap};
+use crate::loader::{LoadError, Loader};
+use crate::natives::NativeFn;
+use crate::parser::ClassParser;
+use crate::types::{MethodAccessFlags, Types};
+use crate::descriptor::{FieldType, MethodDescriptor};
+use crate::Types::*;
+
+/// Errors reported to code embedding the interpreter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JvmError{
+    Load(LoadError),
+    NoSuchMethod(String),
+    NoSuchField(String),
+    /// Arguments or values that don't match the descriptor they are used with.
+    IllegalArgument(String),
+    /// An exception thrown by Java code and not caught: its class, message and stack trace.
+    Exception{class: String, message: Option<String>, stack_trace: Vec<StackTraceElement>},
+    /// Java code called `System.exit` with this status.
+    Exit(i32)
+}
+
+impl fmt::Display for JvmError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        return match self {
+            JvmError::Load(e) => write!(f, "{}: {}", e.java_class().replace('/', "."), e),
+            JvmError::NoSuchMethod(m) => write!(f, "java.lang.NoSuchMethodError: {}", m),
+            JvmError::NoSuchField(m) => write!(f, "java.lang.NoSuchFieldError: {}", m),
+            JvmError::IllegalArgument(m) => write!(f, "java.lang.IllegalArgumentException: {}", m),
+            JvmError::Exception{class, message: Some(message), ..} => write!(f, "{}: {}", class.replace('/', "."), message),
+            JvmError::Exception{class, message: None, ..} => write!(f, "{}", class.replace('/', ".")),
+            JvmError::Exit(status) => write!(f, "Exited with status {}", status)
+        }
+    }
+}
+
+/// A frame of a Java stack trace, as recorded when the exception was created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackTraceElement{
+    /// Binary name of the class, e.g. `java.lang.String`.
+    pub class: String,
+    pub method: String,
+    pub file: Option<String>,
+    /// Source line, negative if unknown. `-2` marks a native method.
+    pub line: i32
+}
+
+impl fmt::Display for StackTraceElement{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{}.{}(", self.class, self.method)?;
+        match (&self.file, self.line) {
+            (_, -2) => write!(f, "Native Method")?,
+            (Some(file), line) if line >= 0 => write!(f, "{}:{}", file, line)?,
+            (Some(file), _) => write!(f, "{}", file)?,
+            (None, _) => write!(f, "Unknown Source")?
+        }
+        return write!(f, ")");
+    }
+}
+
+impl From<LoadError> for JvmError{
+    fn from(e: LoadError) -> Self{
+        if let LoadError::Exit(status) = e{
+            return JvmError::Exit(status);
+        }
+        JvmError::Load(e)
+    }
+}
+
+/// A virtual machine instance: owns its loaded classes, heap and native methods,
+/// so several can run side by side in the same process. A `Jvm` is confined to
+/// the thread that created it.
+pub struct Jvm{
+    pub(crate) loader: Loader,
+    pub(crate) heap: Heap,
+    pub(crate) natives: HashMap<(String, String, String), NativeFn>,
+    /// System properties, as returned by `System.getProperty`.
+    pub properties: BTreeMap<String, String>,
+    /// Frames currently executing, innermost last.
+    pub(crate) frames: Vec<Activation>,
+    /// Locals and operand stacks of the executing frames, outermost first. They are GC roots.
+    pub(crate) stack: Vec<Types>,
+    /// Values kept alive while the VM builds objects out of them.
+    pub(crate) pinned: Vec<Types>,
+    /// References held by the embedder, kept alive until unpinned. They are GC roots.
+    handles: Vec<Types>,
+    /// Whether `RUNTIME_CLASSES` have been, or are being, loaded.
+    runtime_loaded: bool,
+    /// The `OutOfMemoryError` thrown when the heap is full, allocated with the runtime classes.
+    out_of_memory_error: Option<usize>,
+    /// Maximum number of nested Java calls before a `StackOverflowError` is thrown.
+    pub(crate) max_stack_depth: usize
+}
+
+/// Classes the VM creates instances of by itself. They are loaded before any Java code runs, so
+/// that a missing class library is reported to the embedder instead of failing deep inside the VM,
+/// and so that throwing `StackOverflowError` never has to load, and initialise, a class.
+const RUNTIME_CLASSES: &[&str] = &[
+    "java/lang/String",
+    "java/lang/StackTraceElement",
+    "java/lang/AbstractMethodError",
+    "java/lang/ArithmeticException",
+    "java/lang/ArrayIndexOutOfBoundsException",
+    "java/lang/ArrayStoreException",
+    "java/lang/ClassCastException",
+    "java/lang/ClassCircularityError",
+    "java/lang/ClassFormatError",
+    "java/lang/ExceptionInInitializerError",
+    "java/lang/IncompatibleClassChangeError",
+    "java/lang/InternalError",
+    "java/lang/LinkageError",
+    "java/lang/NegativeArraySizeException",
+    "java/lang/NoClassDefFoundError",
+    "java/lang/NoSuchFieldError",
+    "java/lang/NoSuchMethodError",
+    "java/lang/NullPointerException",
+    "java/lang/OutOfMemoryError",
+    "java/lang/StackOverflowError",
+    "java/lang/UnsatisfiedLinkError",
+    "java/lang/UnsupportedClassVersionError"
+];
+
+/// Default maximum number of nested Java calls before a `StackOverflowError` is thrown.
+/// Each call also nests on the native stack, up to about 16 KiB in debug builds, so this
+/// many fit in the 8 MiB of a Rust main thread. See `Jvm::set_max_stack_depth`.
+pub const DEFAULT_MAX_STACK_DEPTH: usize = 256;
+
+/// Maximum number of frames recorded in a stack trace, like HotSpot's `MaxJavaStackTraceDepth`.
+const MAX_STACK_TRACE_DEPTH: usize = 1024;
+
+impl Jvm{
+    /// Creates a VM loading classes from `classpath`, with the built-in natives registered.
+    pub fn new(classpath: ClassPath) -> Self{
+        let mut jvm = Jvm{
+            loader: Loader::new(classpath),
+            heap: Heap::new(),
+            natives: HashMap::new(),
+            properties: BTreeMap::new(),
+            frames: Vec::new(),
+            stack: Vec::new(),
+            pinned: Vec::new(),
+            handles: Vec::new(),
+            runtime_loaded: false,
+            out_of_memory_error: None,
+            max_stack_depth: DEFAULT_MAX_STACK_DEPTH
+        };
+        natives::load_natives(&mut jvm);
+        return jvm;
+    }
+
+    /// Sets the maximum number of bytes the heap may hold.
+    pub fn set_heap_limit(&mut self, limit: usize){
+        self.heap.limit = limit;
+    }
+
+    /// Sets the maximum number of nested Java calls before a `StackOverflowError` is thrown.
+    /// Each call nests on the native stack of the calling thread, which must be large enough
+    /// to hold that many: exceeding it aborts the process.
+    pub fn set_max_stack_depth(&mut self, depth: usize){
+        self.max_stack_depth = depth;
+    }
+
+    /// Registers the implementation of the native method `class.name` with descriptor `desc`.
+    pub fn register_native(&mut self, class: &str, name: &str, desc: &str, f: NativeFn){
+        self.natives.insert((class.to_string(), name.to_string(), desc.to_string()), f);
+    }
+
+    /// Returns the class called `name` (e.g. `java/lang/Object`), loading and
+    /// initialising it from the class path if needed.
+    pub fn load_class(&mut self, name: &str) -> Result<Rc<Class>, LoadError>{
+        if self.loader.erroneous.contains(name){
+            return Err(LoadError::Erroneous(name.replace('/', ".")));
+        }
+        if let Some(c) = self.loader.get(name){
+            return Ok(c);
+        }
+        trace!("Fetching class {}..", name);
+        let data = self.loader.find(name)?;
+        let c = self.define_class(&data)?;
+        if c.name != name{
+            return Err(LoadError::NotFound(format!("{} (wrong name: {})", name.replace('/', "."), c.name.replace('/', "."))));
+        }
+        return Ok(c);
+    }
+
+    /// Parses, links and initialises the class file held in `data`.
+    pub fn define_class(&mut self, data: &[u8]) -> Result<Rc<Class>, LoadError>{
+        // Running `<clinit>` may throw.
+        self.load_runtime()?;
+        let c = ClassParser::new(data).parse()?;
+        let supr = if c.supr.is_empty() {
+            None
+        }else{
+            // Loading the superclass leads back here if the class ends up extending itself.
+            if !self.loader.loading.insert(c.name.clone()){
+                return Err(LoadError::Circularity(c.name.replace('/', ".")));
+            }
+            let supr = self.load_class(&c.supr);
+            self.loader.loading.remove(&c.name);
+            Some(supr?)
+        };
+        let c = self.loader.define(c, supr.as_deref())?;
+
+        for m in &c.methods{
+            if m.name == "<clinit>" && m.desc == "()V"{
+                if c.version[1] >= 51{
+                    let flags = MethodAccessFlags::new(m.flags);
+                    if !flags.STATIC{ continue; }
+                }
+
+                let result = match c.frame(self, m.name.clone(), m.desc.clone(), Vec::new()) {
+                    Ok(mut frame) => frame.exec(self),
+                    Err(ex) => Err(ex)
+                };
+                if let Err(ex) = result{
+                    self.loader.erroneous.insert(c.name.clone());
+                    return Err(match self.exception_error(ex) {
+                        JvmError::Exit(status) => LoadError::Exit(status),
+                        e => LoadError::Initializer(e.to_string())
+                    });
+                }
+                break;
+            }
+        }
+        return Ok(c);
+    }
+
+    /// Like `load_class`, for classes the VM itself depends on: failing to load one is fatal.
+    pub(crate) fn get_class(&mut self, name: &str) -> Rc<Class>{
+        return match self.load_class(name) {
+            Ok(class) => class,
+            Err(e) => panic!("{}: {}", e.java_class().replace('/', "."), e)
+        }
+    }
+
+    /// Like `load_class`, throwing the matching `LinkageError` if the class can't be loaded.
+    pub(crate) fn resolve_class(&mut self, name: &str) -> Result<Rc<Class>, Thrown>{
+        return match self.load_class(name) {
+            Ok(class) => Ok(class),
+            Err(LoadError::Exit(status)) => Err(Thrown::Exit(status)),
+            Err(e) => Err(self.exception(e.java_class(), Some(&e.to_string())))
+        }
+    }
+
+    /// Creates an instance of the `Throwable` subclass `class` with the given message, ready to be thrown.
+    pub fn exception(&mut self, class: &str, message: Option<&str>) -> Thrown{
+        let r = match self.alloc_object(class) {
+            Ok(r) => r,
+            Err(ex) => return ex
+        };
+        // Nothing references the exception until it is thrown.
+        self.pinned.push(Ref(r));
+        let result = self.init_exception(r, message);
+        self.pinned.pop();
+        return match result {
+            Ok(()) => Thrown::Exception(r),
+            Err(ex) => ex
+        }
+    }
+
+    /// Sets the message and stack trace of a newly allocated exception, as its constructor would.
+    fn init_exception(&mut self, r: usize, message: Option<&str>) -> Result<(), Thrown>{
+        if let Some(message) = message{
+            let message = self.alloc_string(message, false)?;
+            self.set_field(r, "message", "Ljava/lang/String;", Ref(message));
+        }
+        return self.fill_in_stack_trace(r);
+    }
+
+    /// Records the frames currently executing as the stack trace of `throwable`, innermost first.
+    /// Like HotSpot, `fillInStackTrace` and the constructors of `throwable` that called it are left out.
+    /// `throwable` must be reachable from a GC root.
+    pub(crate) fn fill_in_stack_trace(&mut self, throwable: usize) -> Result<(), Thrown>{
+        let frames: Vec<(Rc<Class>, String, i32)> = self.frames.iter().rev().map(|frame| {
+            let line = if frame.native { -2 } else { frame.code.line_number(frame.pc).map_or(-1, |line| line as i32) };
+            (frame.class.clone(), frame.method.clone(), line)
+        }).collect();
+
+        let class = self.heap.get(throwable).class.clone();
+        let mut start = frames.iter().take_while(|(_, method, _)| method == "fillInStackTrace").count();
+        if start > 0{
+            while start < frames.len() && frames[start].1 == "<init>" && self.is_assignable(&class, &frames[start].0.name)?{
+                start += 1;
+            }
+        }
+        let frames = &frames[start..frames.len().min(start + MAX_STACK_TRACE_DEPTH)];
+
+        let array = self.alloc_array(ElementType::Class("java/lang/StackTraceElement".to_string()), frames.len())?;
+        self.set_field(throwable, "stackTrace", "[Ljava/lang/StackTraceElement;", Array(array));
+        for (i, (class, method, line)) in frames.iter().enumerate(){
+            let element = self.alloc_object("java/lang/StackTraceElement")?;
+            self.heap.array_mut(array).data[i] = Ref(element);
+            let declaring_class = self.alloc_string(&class.name.replace('/', "."), true)?;
+            self.set_field(element, "declaringClass", "Ljava/lang/String;", Ref(declaring_class));
+            let method = self.alloc_string(method, true)?;
+            self.set_field(element, "methodName", "Ljava/lang/String;", Ref(method));
+            if let Some(file) = &class.source_file{
+                let file = self.alloc_string(file, true)?;
+                self.set_field(element, "fileName", "Ljava/lang/String;", Ref(file));
+            }
+            self.set_field(element, "lineNumber", "I", Int(*line));
+        }
+        return Ok(());
+    }
+
+    /// Returns the instance field `name` of the object `r`, if its class has one.
+    fn get_field(&mut self, r: usize, name: &str, desc: &str) -> Option<Types>{
+        let class = self.heap.get(r).class.clone();
+        let slot = self.get_class(&class).field_slot(name, desc)?;
+        return Some(self.heap.get(r).fields[slot].clone());
+    }
+
+    /// Sets the instance field `name` of the object `r`, which the VM expects its class to have.
+    fn set_field(&mut self, r: usize, name: &str, desc: &str, value: Types){
+        let class = self.heap.get(r).class.clone();
+        let slot = self.get_class(&class).field_slot(name, desc)
+            .unwrap_or_else(|| panic!("{} has no field {}", class, name));
+        self.heap.get_mut(r).fields[slot] = value;
+    }
+
+    /// Keeps the object or array `value` alive across collections until it is passed to `unpin`.
+    /// References returned by `invoke_static`, `get_static_field`, `new_string` and
+    /// `new_string_array` are pinned already.
+    pub fn pin(&mut self, value: &Types){
+        if let Ref(_) | Array(_) = value{
+            self.handles.push(value.clone());
+        }
+    }
+
+    /// Undoes one `pin` of `value`, letting the collector free it once nothing else references it.
+    pub fn unpin(&mut self, value: &Types){
+        let pos = self.handles.iter().rposition(|h| match (h, value) {
+            (Ref(a), Ref(b)) | (Array(a), Array(b)) => a == b,
+            _ => false
+        });
+        if let Some(pos) = pos{
+            self.handles.swap_remove(pos);
+        }
+    }
+
+    /// Pins `value` before handing it to the embedder.
+    fn handle(&mut self, value: Types) -> Types{
+        self.pin(&value);
+        return value;
+    }
+
+    /// Runs the static method `class.name` with descriptor `desc` and returns its result
+    /// (`Types::Void` for `void` methods), pinned.
+    pub fn invoke_static(&mut self, class: &str, name: &str, desc: &str, args: Vec<Types>) -> Result<Types, JvmError>{
+        self.load_runtime()?;
+        let c = self.load_class(class)?;
+        let exists = c.methods.iter().any(|m| m.name == name && m.desc == desc && MethodAccessFlags::new(m.flags).STATIC);
+        if !exists{
+            return Err(JvmError::NoSuchMethod(format!("{}.{}{}", class.replace('/', "."), name, desc)));
+        }
+        let md = MethodDescriptor::parse(desc).ok_or_else(|| JvmError::IllegalArgument(format!("Invalid method descriptor {}", desc)))?;
+        if args.len() != md.params.len(){
+            return Err(JvmError::IllegalArgument(format!("{} takes {} arguments, got {}", name, md.params.len(), args.len())));
+        }
+        for (i, (param, arg)) in md.params.iter().zip(&args).enumerate(){
+            if !self.is_instance(arg, param)?{
+                return Err(JvmError::IllegalArgument(format!("Argument {} of {} must be a {}, got {:?}", i, name, param.descriptor(), arg)));
+            }
+        }
+        let result = match c.frame(self, name.to_string(), desc.to_string(), args) {
+            Ok(mut frame) => frame.exec(self),
+            Err(ex) => Err(ex)
+        };
+        return match result {
+            Ok(value) => Ok(self.handle(value)),
+            Err(ex) => Err(self.exception_error(ex))
+        }
+    }
+
+    /// Returns the value of the static field `class.name` with descriptor `desc`, pinned.
+    pub fn get_static_field(&mut self, class: &str, name: &str, desc: &str) -> Result<Types, JvmError>{
+        let c = self.static_field_class(class, name, desc)?;
+        let value = c.static_field(name, desc).unwrap().value.borrow().clone().unwrap_or(Void);
+        return Ok(self.handle(value));
+    }
+
+    /// Sets the static field `class.name` with descriptor `desc` to `value`.
+    pub fn set_static_field(&mut self, class: &str, name: &str, desc: &str, value: Types) -> Result<(), JvmError>{
+        let c = self.static_field_class(class, name, desc)?;
+        let field = c.static_field(name, desc).unwrap();
+        let typ = FieldType::parse(desc).ok_or_else(|| JvmError::IllegalArgument(format!("Invalid field descriptor {}", desc)))?;
+        if !self.is_instance(&value, &typ)?{
+            return Err(JvmError::IllegalArgument(format!("{} must be a {}, got {:?}", name, desc, value)));
+        }
+        *field.value.borrow_mut() = Some(value);
+        return Ok(());
+    }
+
+    /// Returns the class declaring the static field `class.name` with descriptor `desc`, which may be
+    /// inherited from a superclass or superinterface of `class`.
+    fn static_field_class(&mut self, class: &str, name: &str, desc: &str) -> Result<Rc<Class>, JvmError>{
+        self.load_class(class)?;
+        return match self.resolve_field(class, name, desc) {
+            Ok(Some(c)) if c.static_field(name, desc).is_some() => Ok(c),
+            Ok(_) => Err(JvmError::NoSuchField(format!("{}.{}", class.replace('/', "."), name))),
+            Err(ex) => Err(self.exception_error(ex))
+        }
+    }
+
+    /// Returns a `java/lang/String` holding `s`, pinned.
+    pub fn new_string(&mut self, s: &str) -> Result<Types, JvmError>{
+        self.load_runtime()?;
+        return match self.alloc_string(s, false) {
+            Ok(r) => Ok(self.handle(Ref(r))),
+            Err(ex) => Err(self.exception_error(ex))
+        }
+    }
+
+    /// Returns a `String[]` holding `strings`, pinned.
+    pub fn new_string_array(&mut self, strings: &[String]) -> Result<Types, JvmError>{
+        self.load_runtime()?;
+        let array = match self.alloc_array(ElementType::Class("java/lang/String".to_string()), strings.len()) {
+            Ok(array) => array,
+            Err(ex) => return Err(self.exception_error(ex))
+        };
+        self.pinned.push(Array(array));
+        for (i, s) in strings.iter().enumerate(){
+            match self.alloc_string(s, false) {
+                Ok(r) => self.heap.array_mut(array).data[i] = Ref(r),
+                Err(ex) => {
+                    self.pinned.pop();
+                    return Err(self.exception_error(ex));
+                }
+            }
+        }
+        self.pinned.pop();
+        return Ok(self.handle(Array(array)));
+    }
+
+    /// Loads `RUNTIME_CLASSES`, if not done yet.
+    fn load_runtime(&mut self) -> Result<(), LoadError>{
+        if self.runtime_loaded{
+            return Ok(());
+        }
+        // Set first, as loading them defines classes too.
+        self.runtime_loaded = true;
+        for class in RUNTIME_CLASSES{
+            if let Err(e) = self.load_class(class){
+                self.runtime_loaded = false;
+                return Err(e);
+            }
+        }
+        // Like HotSpot, allocate it up front: once the heap is full there may be no room for it.
+        let error = self.new_out_of_memory_error();
+        self.out_of_memory_error = Some(error);
+        return Ok(());
+    }
+
+    /// Whether `value`, given by the embedder, can be stored in a variable of type `typ`.
+    fn is_instance(&mut self, value: &Types, typ: &FieldType) -> Result<bool, JvmError>{
+        return match (typ, value) {
+            (FieldType::Long, Long(_)) | (FieldType::Float, Float(_)) | (FieldType::Double, Double(_)) => Ok(true),
+            (FieldType::Byte | FieldType::Char | FieldType::Short | FieldType::Int | FieldType::Boolean, Int(_)) => Ok(true),
+            (FieldType::Object(_) | FieldType::Array(_), Null) => Ok(true),
+            (FieldType::Object(_) | FieldType::Array(_), Ref(_) | Array(_) | Str(_) | Class(_)) if self.heap.is_live(value) => {
+                let class = self.class_of(value).expect("Expected a reference");
+                let target = match typ {
+                    FieldType::Object(name) => name.clone(),
+                    _ => typ.descriptor()
+                };
+                self.is_assignable(&class, &target).map_err(|ex| self.exception_error(ex))
+            },
+            _ => Ok(false)
+        }
+    }
+
+    /// Returns the contents of `value` if it is a `java/lang/String`.
+    pub fn string_value(&mut self, value: &Types) -> Option<String>{
+        return match value {
+            Ref(r) if self.heap.get(*r).class == "java/lang/String" => Some(self.java_string(*r)),
+            _ => None
+        }
+    }
+
+    /// Runs a full collection, using every live frame, every static field and pinned values as roots.
+    pub fn collect_garbage(&mut self) -> usize{
+        let mut roots: Vec<Types> = self.stack.clone();
+        for class in self.loader.loaded_classes.values(){
+            roots.extend(class.fields.iter().filter_map(|f| f.value.borrow().clone()));
+        }
+        roots.extend(self.pinned.iter().cloned());
+        roots.extend(self.handles.iter().cloned());
+        roots.extend(self.out_of_memory_error.map(Ref));
+        let freed = self.heap.collect(roots.iter());
+        trace!("GC freed {} bytes, {} in use", freed, self.heap.used);
+        return freed;
+    }
+
+    /// Allocates an instance of `clname`, collecting garbage first if the heap is full.
+    pub(crate) fn alloc_object(&mut self, clname: &str) -> Result<usize, Thrown>{
+        let class = self.resolve_class(clname)?;
+        if let Some(r) = self.heap.new_object(&class){
+            return Ok(r);
+        }
+        self.collect_garbage();
+        return self.heap.new_object(&class).ok_or_else(|| self.out_of_memory());
+    }
+
+    /// Allocates an array, collecting garbage first if the heap is full.
+    pub(crate) fn alloc_array(&mut self, typ: ElementType, len: usize) -> Result<usize, Thrown>{
+        if let Some(r) = self.heap.new_array(typ.clone(), len){
+            return Ok(r);
+        }
+        self.collect_garbage();
+        return self.heap.new_array(typ, len).ok_or_else(|| self.out_of_memory());
+    }
+
+    /// Allocates a string object for `s`, collecting garbage first if the heap is full.
+    /// Interned strings, used for literals, are shared and live as long as the VM.
+    pub(crate) fn alloc_string(&mut self, s: &str, interned: bool) -> Result<usize, Thrown>{
+        return self.alloc_string_utf16(&s.encode_utf16().collect::<Vec<u16>>(), interned);
+    }
+
+    /// Like `alloc_string`, for contents given as UTF-16 code units, which may hold unpaired surrogates.
+    pub(crate) fn alloc_string_utf16(&mut self, chars: &[u16], interned: bool) -> Result<usize, Thrown>{
+        let class = self.get_class("java/lang/String");
+        let alloc = |heap: &mut Heap| if interned { heap.intern(&class, chars) } else { heap.new_string(&class, chars) };
+        if let Some(r) = alloc(&mut self.heap){
+            return Ok(r);
+        }
+        self.collect_garbage();
+        return alloc(&mut self.heap).ok_or_else(|| self.out_of_memory());
+    }
+
+    /// Returns the `OutOfMemoryError` to throw once a collection failed to make room for an allocation.
+    fn out_of_memory(&mut self) -> Thrown{
+        let r = match self.out_of_memory_error {
+            Some(r) => r,
+            // The runtime classes are still being loaded.
+            None => self.new_out_of_memory_error()
+        };
+        return Thrown::Exception(r);
+    }
+
+    /// Allocates an `OutOfMemoryError` without a stack trace. The heap limit doesn't apply,
+    /// as it may already be reached.
+    fn new_out_of_memory_error(&mut self) -> usize{
+        let class = self.get_class("java/lang/OutOfMemoryError");
+        let string = self.get_class("java/lang/String");
+        let limit = std::mem::replace(&mut self.heap.limit, usize::MAX);
+        let r = self.heap.new_object(&class).unwrap();
+        let message: Vec<u16> = "Java heap space".encode_utf16().collect();
+        let message = self.heap.intern(&string, &message).unwrap();
+        self.heap.limit = limit;
+        self.set_field(r, "message", "Ljava/lang/String;", Ref(message));
+        return r;
+    }
+
+    /// Describes an uncaught exception by its class, message and stack trace.
+    pub(crate) fn exception_error(&mut self, ex: Thrown) -> JvmError{
+        let ex = match ex {
+            Thrown::Exception(r) => r,
+            Thrown::Exit(status) => return JvmError::Exit(status)
+        };
+        let class = self.heap.get(ex).class.clone();
+        let message = match self.get_field(ex, "message", "Ljava/lang/String;") {
+            Some(message) => self.string_value(&message),
+            None => None
+        };
+        let elements = match self.get_field(ex, "stackTrace", "[Ljava/lang/StackTraceElement;") {
+            Some(Array(array)) => self.heap.array(array).data.clone(),
+            _ => Vec::new()
+        };
+        let mut stack_trace = Vec::new();
+        for element in elements{
+            if let Ref(r) = element{
+                stack_trace.push(self.stack_trace_element(r));
+            }
+        }
+        return JvmError::Exception{class, message, stack_trace};
+    }
+
+    /// Reads back a `java/lang/StackTraceElement` instance.
+    fn stack_trace_element(&mut self, r: usize) -> StackTraceElement{
+        let string_field = |jvm: &mut Jvm, name: &str| {
+            let value = jvm.get_field(r, name, "Ljava/lang/String;").unwrap_or(Null);
+            jvm.string_value(&value)
+        };
+        let class = string_field(self, "declaringClass").unwrap_or_default();
+        let method = string_field(self, "methodName").unwrap_or_default();
+        let file = string_field(self, "fileName");
+        let line = match self.get_field(r, "lineNumber", "I") {
+            Some(Int(line)) => line,
+            _ => -1
+        };
+        return StackTraceElement{class, method, file, line};
+    }
+
+    /// Returns the runtime class of a reference: a class name, or a descriptor such as `[I` for
+    /// arrays. Returns `None` for `null` and primitives.
+    pub(crate) fn class_of(&self, value: &Types) -> Option<String>{
+        return match value {
+            Ref(r) => Some(self.heap.get(*r).class.clone()),
+            Array(a) => Some(format!("[{}", self.heap.array(*a).typ.descriptor())),
+            Str(_) => Some("java/lang/String".to_string()),
+            Class(_) => Some("java/lang/Class".to_string()),
+            _ => None
+        }
+    }
+
+    /// Whether a reference to an instance of `from` may be assigned to a variable of type `to`
+    /// (JVMS 6.5 `checkcast`). Both are class names or array descriptors.
+    pub(crate) fn is_assignable(&mut self, from: &str, to: &str) -> Result<bool, Thrown>{
+        if from == to || to == "java/lang/Object"{
+            return Ok(true);
+        }
+        if let Some(component) = from.strip_prefix('['){
+            return match to.strip_prefix('[') {
+                Some(to_component) => match (reference_name(component), reference_name(to_component)) {
+                    (Some(c), Some(t)) => self.is_assignable(c, t),
+                    _ => Ok(component == to_component)
+                },
+                None => Ok(to == "java/lang/Cloneable" || to == "java/io/Serializable")
+            }
+        }
+        if to.starts_with('['){
+            return Ok(false);
+        }
+        let class = self.resolve_class(from)?;
+        if !class.supr.is_empty() && self.is_assignable(&class.supr, to)?{
+            return Ok(true);
+        }
+        for interface in &class.interfaces{
+            if self.is_assignable(interface, to)?{
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    /// Resolves the method `name` with descriptor `desc` referenced through the class `class` (JVMS 5.4.3.3):
+    /// looks in the class and its superclasses, then in its superinterfaces. Returns the declaring class.
+    pub(crate) fn resolve_method(&mut self, class: &str, name: &str, desc: &str) -> Result<Rc<Class>, Thrown>{
+        let c = self.resolve_class(class)?;
+        if c.is_interface(){
+            let message = format!("Found interface {}, but class was expected", class.replace('/', "."));
+            return Err(self.exception("java/lang/IncompatibleClassChangeError", Some(&message)));
+        }
+        if let Some(declaring) = self.find_in_superclasses(c.clone(), name, desc, false)?{
+            return Ok(declaring);
+        }
+        return self.resolve_in_superinterfaces(&c, name, desc);
+    }
+
+    /// Resolves the method `name` with descriptor `desc` referenced through the interface `interface`
+    /// (JVMS 5.4.3.4): looks in the interface, then in `java/lang/Object`, then in its superinterfaces.
+    /// Returns the declaring class.
+    pub(crate) fn resolve_interface_method(&mut self, interface: &str, name: &str, desc: &str) -> Result<Rc<Class>, Thrown>{
+        let c = self.resolve_class(interface)?;
+        if !c.is_interface(){
+            let message = format!("Found class {}, but interface was expected", interface.replace('/', "."));
+            return Err(self.exception("java/lang/IncompatibleClassChangeError", Some(&message)));
+        }
+        if c.has_method(name, desc){
+            return Ok(c);
+        }
+        let object = self.resolve_class("java/lang/Object")?;
+        if let Some(m) = object.method(name, desc){
+            let flags = MethodAccessFlags::new(m.flags);
+            if flags.PUBLIC && !flags.STATIC{
+                return Ok(object);
+            }
+        }
+        return self.resolve_in_superinterfaces(&c, name, desc);
+    }
+
+    /// Resolves the field `name` with descriptor `desc` referenced through `class` (JVMS 5.4.3.2): looks
+    /// in the class, then in its superinterfaces, then in its superclass. Returns the declaring class, if any.
+    pub(crate) fn resolve_field(&mut self, class: &str, name: &str, desc: &str) -> Result<Option<Rc<Class>>, Thrown>{
+        let c = self.resolve_class(class)?;
+        if c.fields.iter().any(|f| f.name == name && f.desc == desc){
+            return Ok(Some(c));
+        }
+        for interface in &c.interfaces{
+            if let Some(declaring) = self.resolve_field(interface, name, desc)?{
+                return Ok(Some(declaring));
+            }
+        }
+        if c.supr.is_empty(){
+            return Ok(None);
+        }
+        return self.resolve_field(&c.supr, name, desc);
+    }
+
+    /// Last step of method resolution: picks a maximally-specific superinterface method of `class`,
+    /// preferring a non-abstract one, or throws `NoSuchMethodError`.
+    fn resolve_in_superinterfaces(&mut self, class: &Rc<Class>, name: &str, desc: &str) -> Result<Rc<Class>, Thrown>{
+        let candidates = self.maximally_specific_methods(class, name, desc)?;
+        let default = candidates.iter().find(|i| !MethodAccessFlags::new(i.method(name, desc).unwrap().flags).ABSTRACT);
+        return match default.or(candidates.first()) {
+            Some(declaring) => Ok(declaring.clone()),
+            None => {
+                let message = format!("'{}.{}{}'", class.name.replace('/', "."), name, desc);
+                Err(self.exception("java/lang/NoSuchMethodError", Some(&message)))
+            }
+        }
+    }
+
+    /// Selects the implementation of the instance method `name` with descriptor `desc` to run for an
+    /// object of class `class` (JVMS 5.4.6): the first one declared by the class or a superclass,
+    /// else the only non-abstract maximally-specific superinterface method. Returns the declaring class.
+    pub(crate) fn select_method(&mut self, class: &str, name: &str, desc: &str) -> Result<Rc<Class>, Thrown>{
+        // Arrays only have the methods of Object.
+        let class = if class.starts_with('[') { "java/lang/Object" } else { class };
+        let c = self.resolve_class(class)?;
+        let declaring = match self.find_in_superclasses(c.clone(), name, desc, true)? {
+            Some(declaring) => declaring,
+            None => {
+                let candidates = self.maximally_specific_methods(&c, name, desc)?;
+                let defaults: Vec<Rc<Class>> = candidates.into_iter()
+                    .filter(|i| !MethodAccessFlags::new(i.method(name, desc).unwrap().flags).ABSTRACT)
+                    .collect();
+                if defaults.len() > 1{
+                    let methods: Vec<String> = defaults.iter().map(|i| format!("{}.{}", i.name.replace('/', "."), name)).collect();
+                    let message = format!("Conflicting default methods: {}", methods.join(" "));
+                    return Err(self.exception("java/lang/IncompatibleClassChangeError", Some(&message)));
+                }
+                match defaults.into_iter().next() {
+                    Some(declaring) => declaring,
+                    None => c
+                }
+            }
+        };
+        match declaring.method(name, desc) {
+            Some(m) if !MethodAccessFlags::new(m.flags).ABSTRACT => return Ok(declaring),
+            _ => {
+                let message = format!("{}.{}{}", class.replace('/', "."), name, desc);
+                return Err(self.exception("java/lang/AbstractMethodError", Some(&message)));
+            }
+        }
+    }
+
+    /// Returns the first of `class` and its superclasses that declares the method `name` with
+    /// descriptor `desc`. With `overriding`, static and private methods are skipped, as they
+    /// can't override anything.
+    fn find_in_superclasses(&mut self, class: Rc<Class>, name: &str, desc: &str, overriding: bool) -> Result<Option<Rc<Class>>, Thrown>{
+        let mut current = Some(class);
+        while let Some(c) = current{
+            if let Some(m) = c.method(name, desc){
+                let flags = MethodAccessFlags::new(m.flags);
+                if !overriding || !(flags.STATIC || flags.PRIVATE){
+                    return Ok(Some(c));
+                }
+            }
+            current = if c.supr.is_empty() { None } else { Some(self.resolve_class(&c.supr)?) };
+        }
+        return Ok(None);
+    }
+
+    /// Returns the superinterfaces of `class` declaring a non-private, non-static method `name` with
+    /// descriptor `desc` that no other such superinterface overrides (JVMS 5.4.3.3).
+    fn maximally_specific_methods(&mut self, class: &Rc<Class>, name: &str, desc: &str) -> Result<Vec<Rc<Class>>, Thrown>{
+        let mut interfaces = Vec::new();
+        self.superinterfaces(class, &mut interfaces)?;
+        let candidates: Vec<Rc<Class>> = interfaces.into_iter().filter(|i| match i.method(name, desc) {
+            Some(m) => {
+                let flags = MethodAccessFlags::new(m.flags);
+                !flags.PRIVATE && !flags.STATIC
+            },
+            None => false
+        }).collect();
+        let mut result = Vec::new();
+        for candidate in &candidates{
+            let mut overridden = false;
+            for other in &candidates{
+                if other.name != candidate.name && self.is_assignable(&other.name, &candidate.name)?{
+                    overridden = true;
+                }
+            }
+            if !overridden{
+                result.push(candidate.clone());
+            }
+        }
+        return Ok(result);
+    }
+
+    /// Adds every interface `class` implements, directly or not, to `found`.
+    fn superinterfaces(&mut self, class: &Rc<Class>, found: &mut Vec<Rc<Class>>) -> Result<(), Thrown>{
+        for name in &class.interfaces{
+            if !found.iter().any(|i| i.name == *name){
+                let interface = self.resolve_class(name)?;
+                found.push(interface.clone());
+                self.superinterfaces(&interface, found)?;
+            }
+        }
+        if !class.supr.is_empty(){
+            let supr = self.resolve_class(&class.supr)?;
+            self.superinterfaces(&supr, found)?;
+        }
+        return Ok(());
+    }
+
+    /// Reads the contents of a `java/lang/String` instance.
+    pub(crate) fn java_string(&mut self, r: usize) -> String{
+        let slot = self.get_class("java/lang/String").field_slot("value", "[C").expect("java/lang/String has no value field");
+        return match self.heap.get(r).fields[slot] {
+            Array(value) => {
+                let chars: Vec<u16> = self.heap.array(value).data.iter().map(|c| if let Int(c) = c { *c as u16 } else { 0 }).collect();
+                String::from_utf16_lossy(&chars)
+            },
+            _ => String::new()
+        }
+    }
+}
+
+/// Returns the class name or array descriptor of a reference field descriptor,
+/// or `None` for primitive descriptors.
+fn reference_name(desc: &str) -> Option<&str>{
+    return match desc.strip_prefix('L') {
+        Some(name) => Some(name.trim_end_matches(';')),
+        None if desc.starts_with('[') => Some(desc),
+        None => None
+    }
+}
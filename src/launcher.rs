@@ -0,0 +1,201 @@
+use std::path::Path;
+use crate::classpath::ClassPathEntry;
+
+pub const USAGE: &str = "Usage: jvm [options] <mainclass> [args...]
+           (to execute a class)
+   or  jvm [options] -jar <jarfile> [args...]
+           (to execute a jar file)
+
+Options:
+    -cp, -classpath, --class-path <path>
+                  list of directories and JAR archives to search for class files,
+                  separated by the platform path separator
+    -D<name>=<value>
+                  set a system property
+    -Xmx<size>    set the maximum heap size, e.g. 512k, 64m or 1g
+    -Xbootclasspath:<path>
+                  list of directories and JAR archives holding the class library,
+                  by default $JVM_BOOT_CLASSPATH or the lib directory of the installation
+    -verbose      trace class loading and execution on stderr";
+
+/// What to run and how, as given on the command line.
+#[derive(Debug, Default)]
+pub struct Options{
+    pub classpath: Option<String>,
+    /// Location of the class library, replacing the bundled one.
+    pub boot_classpath: Option<String>,
+    pub main_class: Option<String>,
+    pub jar: Option<String>,
+    pub properties: Vec<(String, String)>,
+    pub heap_limit: Option<usize>,
+    pub verbose: bool,
+    /// Arguments passed on to `main`.
+    pub args: Vec<String>
+}
+
+/// Parses `java`-style arguments (without the program name).
+pub fn parse_args(args: impl IntoIterator<Item=String>) -> Result<Options, String>{
+    let mut opts = Options::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next(){
+        match arg.as_str(){
+            "-cp" | "-classpath" | "--class-path" => {
+                opts.classpath = Some(args.next().ok_or_else(|| format!("{} requires class path specification", arg))?);
+            },
+            "-jar" => {
+                opts.jar = Some(args.next().ok_or("-jar requires jar file specification")?);
+                break;
+            },
+            "-verbose" => opts.verbose = true,
+            "-h" | "-help" | "--help" | "-?" => return Err(String::new()),
+            _ if arg.starts_with("-D") => {
+                let (key, value) = arg[2..].split_once('=').unwrap_or((&arg[2..], ""));
+                opts.properties.push((key.to_string(), value.to_string()));
+            },
+            _ if arg.starts_with("-Xbootclasspath:") => {
+                opts.boot_classpath = Some(arg["-Xbootclasspath:".len()..].to_string());
+            },
+            _ if arg.starts_with("-Xmx") => {
+                opts.heap_limit = Some(parse_size(&arg[4..]).ok_or_else(|| format!("Invalid maximum heap size: {}", arg))?);
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unrecognized option: {}", arg)),
+            _ => {
+                opts.main_class = Some(arg.replace('.', "/"));
+                break;
+            }
+        }
+    }
+    opts.args = args.collect();
+
+    if opts.main_class.is_none() && opts.jar.is_none(){
+        return Err(String::new());
+    }
+    return Ok(opts);
+}
+
+/// Parses a memory size such as `64m`, returning it in bytes.
+fn parse_size(size: &str) -> Option<usize>{
+    let (digits, unit) = match size.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&size[..i], c.to_ascii_lowercase()),
+        _ => (size, 'b')
+    };
+    let multiplier = match unit {
+        'b' => 1,
+        'k' => 1024,
+        'm' => 1024 * 1024,
+        'g' => 1024 * 1024 * 1024,
+        _ => return None
+    };
+    return digits.parse::<usize>().ok()?.checked_mul(multiplier);
+}
+
+/// Reads the `Main-Class` attribute from the manifest of `jar`.
+pub fn jar_main_class(jar: &Path) -> Result<String, String>{
+    let mut entry = ClassPathEntry::open(jar).map_err(|e| format!("Unable to access jarfile {}: {}", jar.display(), e))?;
+    let manifest = entry.read("META-INF/MANIFEST.MF")
+        .ok_or_else(|| format!("no main manifest attribute, in {}", jar.display()))?;
+    let manifest = String::from_utf8_lossy(&manifest);
+
+    // Long values are wrapped onto continuation lines starting with a single space.
+    let mut attributes: Vec<String> = Vec::new();
+    for line in manifest.lines(){
+        match (line.strip_prefix(' '), attributes.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => attributes.push(line.to_string())
+        }
+    }
+    return attributes.iter()
+        .find_map(|attr| attr.strip_prefix("Main-Class:"))
+        .map(|name| name.trim().replace('.', "/"))
+        .ok_or_else(|| format!("no main manifest attribute, in {}", jar.display()));
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::Write;
+
+    fn args(args: &[&str]) -> Vec<String>{
+        return args.iter().map(|a| a.to_string()).collect();
+    }
+
+    #[test]
+    fn main_class_and_arguments(){
+        let opts = parse_args(args(&["-cp", "lib:classes", "-Dkey=value", "-Dflag", "-verbose", "com.example.Main", "-cp", "x"])).unwrap();
+        assert_eq!(opts.classpath.as_deref(), Some("lib:classes"));
+        assert_eq!(opts.properties, vec![("key".to_string(), "value".to_string()), ("flag".to_string(), String::new())]);
+        assert!(opts.verbose);
+        assert_eq!(opts.main_class.as_deref(), Some("com/example/Main"));
+        // Everything after the main class belongs to the program.
+        assert_eq!(opts.args, args(&["-cp", "x"]));
+    }
+
+    #[test]
+    fn jar_and_vm_options(){
+        let opts = parse_args(args(&["-Xmx2m", "-Xbootclasspath:rt", "-jar", "app.jar", "a"])).unwrap();
+        assert_eq!(opts.heap_limit, Some(2 * 1024 * 1024));
+        assert_eq!(opts.boot_classpath.as_deref(), Some("rt"));
+        assert_eq!(opts.jar.as_deref(), Some("app.jar"));
+        assert_eq!(opts.main_class, None);
+        assert_eq!(opts.args, args(&["a"]));
+    }
+
+    #[test]
+    fn bad_arguments(){
+        assert_eq!(parse_args(args(&[])).unwrap_err(), "");
+        assert_eq!(parse_args(args(&["-help"])).unwrap_err(), "");
+        assert_eq!(parse_args(args(&["-cp"])).unwrap_err(), "-cp requires class path specification");
+        assert_eq!(parse_args(args(&["-jar"])).unwrap_err(), "-jar requires jar file specification");
+        assert_eq!(parse_args(args(&["-bogus", "Main"])).unwrap_err(), "Unrecognized option: -bogus");
+        assert_eq!(parse_args(args(&["-Xmx12q", "Main"])).unwrap_err(), "Invalid maximum heap size: -Xmx12q");
+    }
+
+    #[test]
+    fn sizes(){
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("512k"), Some(512 * 1024));
+        assert_eq!(parse_size("64M"), Some(64 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("m"), None);
+        assert_eq!(parse_size("12q"), None);
+        assert_eq!(parse_size("-1k"), None);
+        assert_eq!(parse_size("99999999999999999999g"), None);
+    }
+
+    /// Writes a jar holding only `manifest`, returning its path.
+    fn jar_with_manifest(name: &str, manifest: Option<&str>) -> std::path::PathBuf{
+        let path = std::env::temp_dir().join(format!("jvm-launcher-{}-{}.jar", std::process::id(), name));
+        let mut jar = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        if let Some(manifest) = manifest{
+            jar.start_file("META-INF/MANIFEST.MF", options).unwrap();
+            jar.write_all(manifest.as_bytes()).unwrap();
+        }else{
+            jar.start_file("A.class", options).unwrap();
+        }
+        jar.finish().unwrap();
+        return path;
+    }
+
+    #[test]
+    fn main_class_from_manifest(){
+        let jar = jar_with_manifest("wrapped", Some("Manifest-Version: 1.0\r\nMain-Class: com.example.very.long.pack\r\n age.Main\r\n\r\n"));
+        assert_eq!(jar_main_class(&jar), Ok("com/example/very/long/package/Main".to_string()));
+        std::fs::remove_file(jar).unwrap();
+    }
+
+    #[test]
+    fn missing_main_class(){
+        let jar = jar_with_manifest("no-attribute", Some("Manifest-Version: 1.0\n"));
+        assert!(jar_main_class(&jar).unwrap_err().starts_with("no main manifest attribute"));
+        std::fs::remove_file(jar).unwrap();
+
+        let jar = jar_with_manifest("no-manifest", None);
+        assert!(jar_main_class(&jar).unwrap_err().starts_with("no main manifest attribute"));
+        std::fs::remove_file(jar).unwrap();
+
+        assert!(jar_main_class(Path::new("/nonexistent/app.jar")).unwrap_err().starts_with("Unable to access jarfile"));
+    }
+}
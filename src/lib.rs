@@ -0,0 +1,1037 @@
+#![allow(dead_code, clippy::needless_return)]
+
+extern crate core;
+
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use num_traits::FromPrimitive;
+use opcodes::Opcodes::*;
+use crate::types::{ArrayTypes, Attribute, ClassAccessFlags, Code, Const, ConstPool, Field, FieldAccessFlags, FieldSlot, MethodAccessFlags};
+use crate::heap::ElementType;
+use crate::descriptor::{FieldType, MethodDescriptor};
+use crate::natives::NativeFn;
+use crate::opcodes::Opcodes;
+use crate::Types::*;
+
+pub use crate::types::Types;
+pub use crate::jvm::{Jvm, JvmError, StackTraceElement, DEFAULT_MAX_STACK_DEPTH};
+
+/// Whether `trace!` output is printed, set by `-verbose`.
+pub static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Prints interpreter diagnostics to stderr when running with `-verbose`.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed){
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+mod opcodes;
+pub mod types;
+pub mod loader;
+pub mod natives;
+mod heap;
+mod mutf8;
+pub mod parser;
+pub mod classpath;
+pub mod descriptor;
+pub mod launcher;
+mod jvm;
+
+/// Why a method completed abruptly, propagating up the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Thrown{
+    /// A Java exception: the reference of the thrown `Throwable`.
+    Exception(usize),
+    /// `System.exit` was called with this status. No handler can catch it.
+    Exit(i32)
+}
+
+pub struct Frame{
+    class: Rc<Class>,
+    /// Name of the running method.
+    method: String,
+    ip: u32,
+    code: Rc<Code>,
+    /// Index in `Jvm::stack` of local variable 0. A long or double in slot `n` leaves `Void` in slot `n + 1`.
+    locals: usize,
+    /// Index in `Jvm::stack` of the bottom of the operand stack, which runs up to the top of the
+    /// VM stack. Longs and doubles are one entry there but count as two words.
+    stack: usize,
+    native: bool,
+    native_fn: Option<NativeFn>
+}
+
+/// What the VM knows about an executing frame outside of it, for stack traces.
+pub(crate) struct Activation{
+    pub(crate) class: Rc<Class>,
+    pub(crate) method: String,
+    pub(crate) code: Rc<Code>,
+    /// Address of the instruction being executed.
+    pub(crate) pc: u32,
+    pub(crate) native: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct Class{
+    pub(crate) cp: ConstPool,
+    pub name: String,
+    pub supr: String,
+    pub flags: u16,
+    pub interfaces: Vec<String>,
+    pub fields: Vec<Field>,
+    pub methods: Vec<Field>,
+    pub(crate) attributes: Vec<Attribute>,
+    /// Name of the source file, from the `SourceFile` attribute.
+    pub source_file: Option<String>,
+    pub version: [u16; 2],
+    pub(crate) layout: Vec<FieldSlot>
+}
+
+impl Class{
+    /// Returns the slot of the instance field `name` with descriptor `desc`, as seen from this class.
+    pub fn field_slot(&self, name: &str, desc: &str) -> Option<usize>{
+        return self.layout.iter().rposition(|slot| slot.name == name && slot.desc == desc);
+    }
+
+    /// Returns the static field `name` with descriptor `desc` declared by this class. Fields
+    /// inherited from supertypes are found with `Jvm::resolve_field`.
+    pub fn static_field(&self, name: &str, desc: &str) -> Option<&Field>{
+        return self.fields.iter().find(|f| f.name == name && f.desc == desc && FieldAccessFlags::new(f.flags).STATIC);
+    }
+
+    pub fn has_method(&self, name: &str, desc: &str) -> bool{
+        return self.method(name, desc).is_some();
+    }
+
+    /// Returns the method `name` with descriptor `desc` declared by this class.
+    pub fn method(&self, name: &str, desc: &str) -> Option<&Field>{
+        return self.methods.iter().find(|m| m.name == name && m.desc == desc);
+    }
+
+    pub fn is_interface(&self) -> bool{
+        return ClassAccessFlags::new(self.flags).INTERFACE;
+    }
+
+    /// Sets up a frame running `method` with the given arguments, one per parameter, pushing
+    /// its locals onto the VM stack. The frame must then be run with `Frame::exec`.
+    pub fn frame(self: &Rc<Self>, jvm: &mut Jvm, method: String, desc: String, args: Vec<Types>) -> Result<Frame, Thrown>{
+        let base = jvm.stack.len();
+        let count = args.len();
+        jvm.stack.extend(args);
+        let frame = self.frame_on_stack(jvm, &method, &desc, count);
+        if frame.is_err(){
+            jvm.stack.truncate(base);
+        }
+        return frame;
+    }
+
+    /// Like `frame`, for arguments that are the topmost `count` entries of the VM stack. They stay
+    /// there, becoming the first locals of the frame, so the GC sees them throughout the call.
+    pub(crate) fn frame_on_stack(self: &Rc<Self>, jvm: &mut Jvm, method: &str, desc: &str, count: usize) -> Result<Frame, Thrown>{
+        let base = jvm.stack.len() - count;
+        trace!("Loading method {}::{} with locals {:?}",self.name, method, &jvm.stack[base..]);
+        for m in &self.methods{
+            if m.name == method && m.desc == desc{
+                if MethodAccessFlags::new(m.flags).NATIVE{
+                    trace!("Loading native...");
+                    let native = match jvm.natives.get(&(self.name.clone(), m.name.clone(), m.desc.clone())) {
+                        Some(native) => *native,
+                        None => {
+                            let message = format!("{}.{}{}", self.name.replace('/', "."), m.name, m.desc);
+                            return Err(jvm.exception("java/lang/UnsatisfiedLinkError", Some(&message)));
+                        }
+                    };
+                    return Ok(Frame {
+                        native_fn: Some(native),
+                        class: self.clone(),
+                        method: m.name.clone(),
+                        ip: 0,
+                        code: Rc::default(),
+                        locals: base,
+                        stack: jvm.stack.len(),
+                        native: true,
+                    })
+                }
+                if let Some(code) = &m.code{
+                    trace!("max locals: {}", code.max_locals);
+                    // Arguments are passed one per parameter; long and double ones take two local slots.
+                    let md = match MethodDescriptor::parse(&m.desc) {
+                        Some(md) => md,
+                        None => return Err(jvm.exception("java/lang/ClassFormatError", Some(&format!("Invalid method descriptor {}", m.desc))))
+                    };
+                    let receiver = !MethodAccessFlags::new(m.flags).STATIC;
+                    let sizes = receiver.then_some(1).into_iter().chain(md.params.iter().map(FieldType::slots));
+                    let mut slot = base;
+                    for size in sizes.take(count){
+                        if size == 2{
+                            jvm.stack.insert(slot + 1, Void);
+                        }
+                        slot += size;
+                    }
+                    jvm.stack.resize(base + code.max_locals as usize, Int(0));
+                    return Ok(Frame{
+                        ip: 0,
+                        code: code.clone(),
+                        locals: base,
+                        stack: jvm.stack.len(),
+                        class: self.clone(),
+                        method: m.name.clone(),
+                        native: false,
+                        native_fn: None
+                    });
+                }
+            }
+        }
+        let message = format!("'{}.{}{}'", self.name.replace('/', "."), method, desc);
+        return Err(jvm.exception("java/lang/NoSuchMethodError", Some(&message)));
+    }
+}
+
+/// Evaluates the comparison made by an `IF<cond>` or `IF_ICMP<cond>` opcode.
+fn int_condition(op: Opcodes, a: i32, b: i32) -> bool{
+    return match op {
+        IFEQ | IF_ICMPEQ => a == b,
+        IFNE | IF_ICMPNE => a != b,
+        IFLT | IF_ICMPLT => a < b,
+        IFGE | IF_ICMPGE => a >= b,
+        IFGT | IF_ICMPGT => a > b,
+        IFLE | IF_ICMPLE => a <= b,
+        _ => unreachable!("{:?} is not an int comparison", op)
+    }
+}
+
+impl Frame{
+    pub fn pop(&mut self, jvm: &mut Jvm) -> Types{
+        if jvm.stack.len() <= self.stack{
+            panic!("Stack empty");
+        }
+        return jvm.stack.pop().unwrap();
+    }
+
+    /// Returns the operand stack entry `depth` entries below the top, leaving it in place.
+    fn peek(&self, jvm: &Jvm, depth: usize) -> Types{
+        if jvm.stack.len() <= self.stack + depth{
+            panic!("Stack empty");
+        }
+        return jvm.stack[jvm.stack.len() - 1 - depth].clone();
+    }
+
+    /// Runs the method to completion, returning its result or the exception it threw.
+    /// Its locals and operand stack are popped off the VM stack either way.
+    pub fn exec(&mut self, jvm: &mut Jvm) -> Result<Types, Thrown>{
+        let ret = if jvm.frames.len() >= jvm.max_stack_depth{
+            Err(jvm.exception("java/lang/StackOverflowError", None))
+        }else{
+            jvm.frames.push(Activation{class: self.class.clone(), method: self.method.clone(), code: self.code.clone(), pc: 0, native: self.native});
+            let ret = self.run(jvm);
+            jvm.frames.pop();
+            ret
+        };
+        jvm.stack.truncate(self.locals);
+        return ret;
+    }
+
+    fn run(&mut self, jvm: &mut Jvm) -> Result<Types, Thrown>{
+        if self.native{
+            let class = self.class.clone();
+            let args = jvm.stack[self.locals..].to_vec();
+            return self.native_fn.unwrap()(jvm, &class, args);
+        }
+
+        loop{
+            // Handlers are looked up by the address of the instruction that threw.
+            let pc = self.ip;
+            jvm.frames.last_mut().expect("Frame not registered").pc = pc;
+            match self.step(jvm) {
+                Ok(Some(ret)) => return Ok(ret),
+                Ok(None) => {},
+                Err(ex) => self.catch(jvm, pc, ex)?
+            }
+        }
+    }
+
+    /// Executes the instruction at `ip`, returning the method's result if it returns.
+    fn step(&mut self, jvm: &mut Jvm) -> Result<Option<Types>, Thrown>{
+        // Branch offsets are relative to the address of the branch opcode.
+        let pc = self.ip;
+        let op: Opcodes = FromPrimitive::from_u8(self.code.bytecode[self.ip as usize]).expect("Invalid opcode.");
+        trace!("Executing opcode {:?} ({}) with stack {:?}", op, op as u8, &jvm.stack[self.stack..]);
+
+        match op{
+            NOP => {}
+            ACONST_NULL => jvm.stack.push(Null),
+            ICONST_M1 => jvm.stack.push(Int(-1)),
+            ICONST_0 => jvm.stack.push(Int(0)),
+            ICONST_1 => jvm.stack.push(Int(1)),
+            ICONST_2 => jvm.stack.push(Int(2)),
+            ICONST_3 => jvm.stack.push(Int(3)),
+            ICONST_4 => jvm.stack.push(Int(4)),
+            ICONST_5 => jvm.stack.push(Int(5)),
+            LCONST_0 => jvm.stack.push(Long(0)),
+            LCONST_1 => jvm.stack.push(Long(1)),
+            FCONST_0 => jvm.stack.push(Float(0.0)),
+            FCONST_1 => jvm.stack.push(Float(1.0)),
+            FCONST_2 => jvm.stack.push(Float(2.0)),
+            DCONST_0 => jvm.stack.push(Double(0.0)),
+            DCONST_1 => jvm.stack.push(Double(1.0)),
+            BIPUSH => {
+                self.ip += 1;
+                let val = self.code.bytecode[self.ip as usize];
+                jvm.stack.push(Int(val as i8 as i32))
+            },
+            SIPUSH => {
+                let val = i16::from_be_bytes(self.read_bytes());
+                jvm.stack.push(Int(val as i32))
+            },
+            ILOAD_0 | LLOAD_0 | FLOAD_0 | DLOAD_0 | ALOAD_0 => self.load(jvm, 0),
+            ILOAD_1 | LLOAD_1 | FLOAD_1 | DLOAD_1 | ALOAD_1 => self.load(jvm, 1),
+            ILOAD_2 | LLOAD_2 | FLOAD_2 | DLOAD_2 | ALOAD_2 => self.load(jvm, 2),
+            ILOAD_3 | LLOAD_3 | FLOAD_3 | DLOAD_3 | ALOAD_3 => self.load(jvm, 3),
+            ILOAD | LLOAD | FLOAD | DLOAD | ALOAD => {
+                self.ip += 1;
+                self.load(jvm, self.code.bytecode[self.ip as usize] as usize);
+            },
+            ISTORE_0 | LSTORE_0 | FSTORE_0 | DSTORE_0 | ASTORE_0 => self.store(jvm, 0),
+            ISTORE_1 | LSTORE_1 | FSTORE_1 | DSTORE_1 | ASTORE_1 => self.store(jvm, 1),
+            ISTORE_2 | LSTORE_2 | FSTORE_2 | DSTORE_2 | ASTORE_2 => self.store(jvm, 2),
+            ISTORE_3 | LSTORE_3 | FSTORE_3 | DSTORE_3 | ASTORE_3 => self.store(jvm, 3),
+            ISTORE | LSTORE | FSTORE | DSTORE | ASTORE => {
+                self.ip += 1;
+                self.store(jvm, self.code.bytecode[self.ip as usize] as usize);
+            },
+            // Widens the local index of the next instruction, and the constant too for IINC.
+            WIDE => {
+                self.ip += 1;
+                let op: Opcodes = FromPrimitive::from_u8(self.code.bytecode[self.ip as usize]).expect("Invalid opcode.");
+                let idx = u16::from_be_bytes(self.read_bytes()) as usize;
+                match op{
+                    ILOAD | LLOAD | FLOAD | DLOAD | ALOAD => self.load(jvm, idx),
+                    ISTORE | LSTORE | FSTORE | DSTORE | ASTORE => self.store(jvm, idx),
+                    IINC => {
+                        let inc = i16::from_be_bytes(self.read_bytes());
+                        self.iinc(jvm, idx, inc as i32);
+                    },
+                    opc => panic!("Invalid wide opcode {:?}", opc)
+                }
+            },
+            IALOAD | LALOAD | FALOAD | DALOAD | AALOAD | BALOAD | CALOAD | SALOAD => {
+                let (array, idx) = self.pop_array_index(jvm)?;
+                jvm.stack.push(jvm.heap.array(array).data[idx].clone());
+            },
+            IASTORE | LASTORE | FASTORE | DASTORE | BASTORE | CASTORE | SASTORE => {
+                let val = self.pop(jvm);
+                let (array, idx) = self.pop_array_index(jvm)?;
+                let array = jvm.heap.array_mut(array);
+                // BASTORE serves both byte and boolean arrays; booleans keep only the low bit.
+                array.data[idx] = match (&array.typ, val) {
+                    (ElementType::Primitive(ArrayTypes::BOOLEAN), Int(v)) => Int(v & 1),
+                    (ElementType::Primitive(ArrayTypes::BYTE), Int(v)) => Int(v as i8 as i32),
+                    (ElementType::Primitive(ArrayTypes::CHAR), Int(v)) => Int(v as u16 as i32),
+                    (ElementType::Primitive(ArrayTypes::SHORT), Int(v)) => Int(v as i16 as i32),
+                    (_, val) => val
+                };
+            },
+            AASTORE => {
+                let val = self.pop(jvm);
+                let (array, idx) = self.pop_array_index(jvm)?;
+                if let (Some(class), ElementType::Class(component)) = (jvm.class_of(&val), jvm.heap.array(array).typ.clone()){
+                    // Loading the component type may run a GC, so keep the array and the value alive.
+                    jvm.pinned.extend([Array(array), val.clone()]);
+                    let assignable = jvm.is_assignable(&class, &component);
+                    jvm.pinned.truncate(jvm.pinned.len() - 2);
+                    if !assignable?{
+                        return Err(jvm.exception("java/lang/ArrayStoreException", Some(&class.replace('/', "."))));
+                    }
+                }
+                jvm.heap.array_mut(array).data[idx] = val;
+            },
+            ARRAYLENGTH => {
+                match self.pop(jvm){
+                    Array(array) => jvm.stack.push(Int(jvm.heap.array(array).data.len() as i32)),
+                    Null => return Err(jvm.exception("java/lang/NullPointerException", Some("Cannot read the array length because the array is null"))),
+                    val => panic!("Expected an array, got {:?}", val)
+                }
+            },
+            POP => {self.pop(jvm);},
+            POP2 => {
+                let words = self.peek_words(jvm, 2);
+                jvm.stack.truncate(jvm.stack.len() - words);
+            },
+            DUP => {
+                let val = self.pop(jvm);
+                jvm.stack.push(val.clone());
+                jvm.stack.push(val);
+            },
+            DUP_X1 => {
+                let top = self.peek_words(jvm, 1);
+                let under = self.peek_words_below(jvm, top, 1);
+                self.dup_under(jvm, top, under);
+            },
+            DUP_X2 => {
+                let top = self.peek_words(jvm, 1);
+                let under = self.peek_words_below(jvm, top, 2);
+                self.dup_under(jvm, top, under);
+            },
+            SWAP => {
+                if self.peek_words(jvm, 2) != 2{
+                    panic!("Can't swap a long or double.");
+                }
+                let len = jvm.stack.len();
+                jvm.stack.swap(len - 1, len - 2);
+            },
+            DUP2 => {
+                let words = self.peek_words(jvm, 2);
+                jvm.stack.extend_from_within(jvm.stack.len() - words..);
+            },
+            DUP2_X1 => {
+                let top = self.peek_words(jvm, 2);
+                let under = self.peek_words_below(jvm, top, 1);
+                self.dup_under(jvm, top, under);
+            },
+            DUP2_X2 => {
+                let top = self.peek_words(jvm, 2);
+                let under = self.peek_words_below(jvm, top, 2);
+                self.dup_under(jvm, top, under);
+            },
+            IADD => self.int_op(jvm, i32::wrapping_add),
+            ISUB => self.int_op(jvm, i32::wrapping_sub),
+            IMUL => self.int_op(jvm, i32::wrapping_mul),
+            IDIV | IREM => {
+                let b = self.pop_int(jvm);
+                let a = self.pop_int(jvm);
+                if b == 0{
+                    return Err(jvm.exception("java/lang/ArithmeticException", Some("/ by zero")));
+                }
+                jvm.stack.push(Int(if op == IDIV { a.wrapping_div(b) } else { a.wrapping_rem(b) }));
+            },
+            INEG => {
+                let a = self.pop_int(jvm);
+                jvm.stack.push(Int(a.wrapping_neg()));
+            },
+            // Shift distances are masked to their low five (int) or six (long) bits.
+            ISHL => self.int_op(jvm, |a, b| a.wrapping_shl(b as u32)),
+            ISHR => self.int_op(jvm, |a, b| a.wrapping_shr(b as u32)),
+            IUSHR => self.int_op(jvm, |a, b| (a as u32).wrapping_shr(b as u32) as i32),
+            IAND => self.int_op(jvm, |a, b| a & b),
+            IOR => self.int_op(jvm, |a, b| a | b),
+            IXOR => self.int_op(jvm, |a, b| a ^ b),
+            IINC => {
+                let idx = self.code.bytecode[self.ip as usize + 1] as usize;
+                let inc = self.code.bytecode[self.ip as usize + 2] as i8 as i32;
+                self.ip += 2;
+                self.iinc(jvm, idx, inc);
+            },
+            LADD => self.long_op(jvm, i64::wrapping_add),
+            LSUB => self.long_op(jvm, i64::wrapping_sub),
+            LMUL => self.long_op(jvm, i64::wrapping_mul),
+            LDIV | LREM => {
+                let b = self.pop_long(jvm);
+                let a = self.pop_long(jvm);
+                if b == 0{
+                    return Err(jvm.exception("java/lang/ArithmeticException", Some("/ by zero")));
+                }
+                jvm.stack.push(Long(if op == LDIV { a.wrapping_div(b) } else { a.wrapping_rem(b) }));
+            },
+            LNEG => {
+                let a = self.pop_long(jvm);
+                jvm.stack.push(Long(a.wrapping_neg()));
+            },
+            LSHL | LSHR | LUSHR => {
+                let b = self.pop_int(jvm) as u32;
+                let a = self.pop_long(jvm);
+                jvm.stack.push(Long(match op {
+                    LSHL => a.wrapping_shl(b),
+                    LSHR => a.wrapping_shr(b),
+                    _ => (a as u64).wrapping_shr(b) as i64
+                }));
+            },
+            LAND => self.long_op(jvm, |a, b| a & b),
+            LOR => self.long_op(jvm, |a, b| a | b),
+            LXOR => self.long_op(jvm, |a, b| a ^ b),
+            // `as` saturates float to integer conversions and maps NaN to 0, like Java.
+            I2L => { let a = self.pop_int(jvm); jvm.stack.push(Long(a as i64)) },
+            I2F => { let a = self.pop_int(jvm); jvm.stack.push(Float(a as f32)) },
+            I2D => { let a = self.pop_int(jvm); jvm.stack.push(Double(a as f64)) },
+            L2I => { let a = self.pop_long(jvm); jvm.stack.push(Int(a as i32)) },
+            L2F => { let a = self.pop_long(jvm); jvm.stack.push(Float(a as f32)) },
+            L2D => { let a = self.pop_long(jvm); jvm.stack.push(Double(a as f64)) },
+            F2I => { let a = self.pop_float(jvm); jvm.stack.push(Int(a as i32)) },
+            F2L => { let a = self.pop_float(jvm); jvm.stack.push(Long(a as i64)) },
+            F2D => { let a = self.pop_float(jvm); jvm.stack.push(Double(a as f64)) },
+            D2I => { let a = self.pop_double(jvm); jvm.stack.push(Int(a as i32)) },
+            D2L => { let a = self.pop_double(jvm); jvm.stack.push(Long(a as i64)) },
+            D2F => { let a = self.pop_double(jvm); jvm.stack.push(Float(a as f32)) },
+            I2B => { let a = self.pop_int(jvm); jvm.stack.push(Int(a as i8 as i32)) },
+            I2C => { let a = self.pop_int(jvm); jvm.stack.push(Int(a as u16 as i32)) },
+            I2S => { let a = self.pop_int(jvm); jvm.stack.push(Int(a as i16 as i32)) },
+            LCMP => {
+                let b = self.pop_long(jvm);
+                let a = self.pop_long(jvm);
+                jvm.stack.push(Int(a.cmp(&b) as i32));
+            },
+            // Rust's float operators follow IEEE 754 like Java's, and `%` truncates like Java's.
+            FADD => self.float_op(jvm, |a, b| a + b),
+            FSUB => self.float_op(jvm, |a, b| a - b),
+            FMUL => self.float_op(jvm, |a, b| a * b),
+            FDIV => self.float_op(jvm, |a, b| a / b),
+            FREM => self.float_op(jvm, |a, b| a % b),
+            FNEG => {
+                let a = self.pop_float(jvm);
+                jvm.stack.push(Float(-a));
+            },
+            DADD => self.double_op(jvm, |a, b| a + b),
+            DSUB => self.double_op(jvm, |a, b| a - b),
+            DMUL => self.double_op(jvm, |a, b| a * b),
+            DDIV => self.double_op(jvm, |a, b| a / b),
+            DREM => self.double_op(jvm, |a, b| a % b),
+            DNEG => {
+                let a = self.pop_double(jvm);
+                jvm.stack.push(Double(-a));
+            },
+            // The L variants push -1 if either value is NaN, the G variants 1.
+            FCMPL | FCMPG => {
+                let b = self.pop_float(jvm);
+                let a = self.pop_float(jvm);
+                jvm.stack.push(Int(a.partial_cmp(&b).map_or(if op == FCMPG { 1 } else { -1 }, |o| o as i32)));
+            },
+            DCMPL | DCMPG => {
+                let b = self.pop_double(jvm);
+                let a = self.pop_double(jvm);
+                jvm.stack.push(Int(a.partial_cmp(&b).map_or(if op == DCMPG { 1 } else { -1 }, |o| o as i32)));
+            },
+            // LDC_W is LDC with a wide index, for classes with more than 255 constants.
+            LDC | LDC_W => {
+                let idx = if op == LDC { self.read_bytes::<1>()[0] as u16 } else { u16::from_be_bytes(self.read_bytes()) };
+
+                match self.class.cp.get(idx) {
+                    Const::Str(s, _) => { jvm.stack.push(Str(s)) }
+                    Const::StrIndex(str_idx) => {
+                        let units = match self.class.cp.get(str_idx) {
+                            Const::Str(_, units) => units,
+                            _ => panic!("Expected a Utf8 constant at {}.", str_idx)
+                        };
+                        let string = jvm.alloc_string_utf16(&units, true)?;
+                        jvm.stack.push(Ref(string));
+                    }
+                    Const::Int(i) => { jvm.stack.push(Int(i)) }
+                    Const::Float(f) => { jvm.stack.push(Float(f)) }
+                    Const::Class(name_idx) => {
+                        let name = jvm.loader.resolve(&self.class.cp, name_idx as usize);
+                        let class = jvm.resolve_class(&name)?;
+                        jvm.stack.push(Class(class.name.clone()));
+                    }
+                    // Loadable, but resolving them needs `java.lang.invoke`, which the class library lacks.
+                    Const::MethodType(_) | Const::MethodHandle(_) | Const::Dynamic(_) => {
+                        let message = format!("ldc of constant #{} in {} is not supported", idx, self.class.name.replace('/', "."));
+                        return Err(jvm.exception("java/lang/InternalError", Some(&message)));
+                    }
+                    _ => {
+                        let message = format!("Constant #{} in {} is not loadable", idx, self.class.name.replace('/', "."));
+                        return Err(jvm.exception("java/lang/ClassFormatError", Some(&message)));
+                    }
+                }
+            }
+            LDC2_W => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+                match self.class.cp.get(idx) {
+                    Const::Long(l) => { jvm.stack.push(Long(l)) }
+                    Const::Double(d) => { jvm.stack.push(Double(d)) }
+                    _ => {
+                        let message = format!("Constant #{} in {} is not a long or a double", idx, self.class.name.replace('/', "."));
+                        return Err(jvm.exception("java/lang/ClassFormatError", Some(&message)));
+                    }
+                }
+            },
+            IFEQ | IFNE | IFLT | IFGE | IFGT | IFLE => {
+                let target = self.branch_target(pc);
+                let a = self.pop_int(jvm);
+                if int_condition(op, a, 0){
+                    self.ip = target;
+                    return Ok(None);
+                }
+            },
+            IF_ICMPEQ | IF_ICMPNE | IF_ICMPLT | IF_ICMPGE | IF_ICMPGT | IF_ICMPLE => {
+                let target = self.branch_target(pc);
+                let b = self.pop_int(jvm);
+                let a = self.pop_int(jvm);
+                if int_condition(op, a, b){
+                    self.ip = target;
+                    return Ok(None);
+                }
+            },
+            IF_ACMPEQ | IF_ACMPNE => {
+                let target = self.branch_target(pc);
+                let b = self.pop(jvm);
+                let a = self.pop(jvm);
+                if a.same_ref(&b) == (op == IF_ACMPEQ){
+                    self.ip = target;
+                    return Ok(None);
+                }
+            },
+            IFNULL | IFNONNULL => {
+                let target = self.branch_target(pc);
+                let is_null = matches!(self.pop(jvm), Null);
+                if is_null == (op == IFNULL){
+                    self.ip = target;
+                    return Ok(None);
+                }
+            },
+            GOTO => {
+                self.ip = self.branch_target(pc);
+                return Ok(None);
+            },
+            GOTO_W => {
+                let offset = i32::from_be_bytes(self.read_bytes());
+                self.ip = pc.wrapping_add_signed(offset);
+                return Ok(None);
+            },
+            TABLESWITCH => {
+                // Operands start at the next multiple of four from the start of the code.
+                let base = (pc + 4) & !3;
+                let default = self.i32_at(base);
+                let low = self.i32_at(base + 4);
+                let high = self.i32_at(base + 8);
+                let key = self.pop_int(jvm);
+                let offset = if key >= low && key <= high {
+                    self.i32_at(base + 12 + 4 * (key - low) as u32)
+                }else{
+                    default
+                };
+                self.ip = pc.wrapping_add_signed(offset);
+                return Ok(None);
+            },
+            LOOKUPSWITCH => {
+                let base = (pc + 4) & !3;
+                let default = self.i32_at(base);
+                let npairs = self.i32_at(base + 4) as u32;
+                let key = self.pop_int(jvm);
+                let offset = (0..npairs)
+                    .map(|i| base + 8 + 8 * i)
+                    .find(|&pair| self.i32_at(pair) == key)
+                    .map_or(default, |pair| self.i32_at(pair + 4));
+                self.ip = pc.wrapping_add_signed(offset);
+                return Ok(None);
+            },
+            ATHROW => {
+                match self.pop(jvm){
+                    Ref(r) => return Err(Thrown::Exception(r)),
+                    Null => return Err(jvm.exception("java/lang/NullPointerException", Some("Cannot throw exception because the value is null"))),
+                    val => panic!("Expected a Throwable, got {:?}", val)
+                }
+            },
+            IRETURN | DRETURN | LRETURN | ARETURN | FRETURN => return Ok(Some(self.pop(jvm))),
+            RETURN => return Ok(Some(Void)),
+            GETFIELD => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+
+                let (clname, fname, fdesc) = self.handle_fmi(jvm, self.class.cp.get(idx));
+                let slot = self.field_slot(jvm, &clname, &fname, &fdesc)?;
+                match self.pop(jvm){
+                    Ref(r) => jvm.stack.push(jvm.heap.get(r).fields[slot].clone()),
+                    Null => return Err(jvm.exception("java/lang/NullPointerException", Some(&format!("Cannot read field \"{}\" because the value is null", fname)))),
+                    val => panic!("Expected an object reference, got {:?}", val)
+                }
+            },
+            PUTFIELD => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+
+                let (clname, fname, fdesc) = self.handle_fmi(jvm, self.class.cp.get(idx));
+                let slot = self.field_slot(jvm, &clname, &fname, &fdesc)?;
+                let value = self.pop(jvm);
+                match self.pop(jvm){
+                    Ref(r) => {
+                        trace!("{}::{}-{} set to {:?}", clname, fname, fdesc, value);
+                        jvm.heap.get_mut(r).fields[slot] = value;
+                    },
+                    Null => return Err(jvm.exception("java/lang/NullPointerException", Some(&format!("Cannot assign field \"{}\" because the value is null", fname)))),
+                    val => panic!("Expected an object reference, got {:?}", val)
+                }
+            },
+            GETSTATIC => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+
+                let field_ref = self.class.cp.get(idx);
+                let (clname, fname, ftype) = self.handle_fmi(jvm, field_ref);
+                trace!("{}: {}-{}", clname, fname, ftype);
+                let class = self.static_field_class(jvm, &clname, &fname, &ftype)?;
+                let field = class.static_field(&fname, &ftype).unwrap();
+                trace!("{}, {}-{} : {:?}", self.class.name, field.name, field.desc, field.value);
+                jvm.stack.push(field.value.borrow().clone().unwrap_or(Void));
+            },
+            PUTSTATIC => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+                let (clname, fname, ftype) = self.handle_fmi(jvm, self.class.cp.get(idx));
+                let cl = self.static_field_class(jvm, &clname, &fname, &ftype)?;
+                let field = cl.static_field(&fname, &ftype).unwrap();
+                let val = self.pop(jvm);
+                trace!("{}::{} set to {:?}", clname, fname, val);
+                *field.value.borrow_mut() = Some(val);
+            },
+            INVOKEVIRTUAL | INVOKESPECIAL | INVOKESTATIC | INVOKEINTERFACE => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+                if op == INVOKEINTERFACE{
+                    // The argument count and a zero byte, both redundant.
+                    self.read_bytes::<2>();
+                }
+
+                let method_ref = self.class.cp.get(idx);
+                let interface = matches!(method_ref, Const::InterfaceMethodRef(_));
+                let (clname, mname, typ) = self.handle_fmi(jvm, method_ref);
+                trace!("Resolving method {}.{}{}..", clname, mname, typ);
+                let declaring = if interface {
+                    jvm.resolve_interface_method(&clname, &mname, &typ)?
+                } else {
+                    jvm.resolve_method(&clname, &mname, &typ)?
+                };
+                let flags = MethodAccessFlags::new(declaring.method(&mname, &typ).unwrap().flags);
+                if flags.STATIC != (op == INVOKESTATIC){
+                    let expected = if flags.STATIC { "Expecting non-static method" } else { "Expected static method" };
+                    let message = format!("{} {}.{}{}", expected, declaring.name.replace('/', "."), mname, typ);
+                    return Err(jvm.exception("java/lang/IncompatibleClassChangeError", Some(&message)));
+                }
+
+                // Selecting the method may load classes and run a GC, so the receiver and the
+                // arguments stay on the stack, and become the locals of the callee.
+                let md = MethodDescriptor::parse(&typ)
+                    .unwrap_or_else(|| panic!("java.lang.ClassFormatError: Invalid method descriptor {}", typ));
+                let receiver = (op != INVOKESTATIC).then(|| self.peek(jvm, md.params.len()));
+                if matches!(receiver, Some(Null)){
+                    let message = format!("Cannot invoke \"{}.{}()\" because the value is null", clname.replace('/', "."), mname);
+                    return Err(jvm.exception("java/lang/NullPointerException", Some(&message)));
+                }
+                let c = match op {
+                    INVOKEVIRTUAL | INVOKEINTERFACE if !flags.PRIVATE => {
+                        let receiver = receiver.as_ref().and_then(|r| jvm.class_of(r)).expect("Expected an object reference");
+                        if op == INVOKEINTERFACE && !jvm.is_assignable(&receiver, &clname)?{
+                            let message = format!("Class {} does not implement the requested interface {}", receiver.replace('/', "."), clname.replace('/', "."));
+                            return Err(jvm.exception("java/lang/IncompatibleClassChangeError", Some(&message)));
+                        }
+                        jvm.select_method(&receiver, &mname, &typ)?
+                    },
+                    // `I.super.m()` runs the resolved default method.
+                    INVOKESPECIAL if interface && MethodAccessFlags::new(declaring.method(&mname, &typ).unwrap().flags).ABSTRACT => {
+                        let message = format!("{}.{}{}", declaring.name.replace('/', "."), mname, typ);
+                        return Err(jvm.exception("java/lang/AbstractMethodError", Some(&message)));
+                    },
+                    // `super.m()`: look the method up again from the direct superclass of the caller.
+                    INVOKESPECIAL if !interface && mname != "<init>" && clname != self.class.name && ClassAccessFlags::new(self.class.flags).SUPER
+                        && jvm.is_assignable(&self.class.name, &clname)? => {
+                        let supr = self.class.supr.clone();
+                        jvm.select_method(&supr, &mname, &typ)?
+                    },
+                    _ => declaring
+                };
+                let count = md.params.len() + receiver.is_some() as usize;
+                if jvm.stack.len() < self.stack + count{
+                    panic!("Stack underflow calling a method with descriptor {}", typ);
+                }
+                let mut frame = c.frame_on_stack(jvm, &mname, &typ, count)?;
+                match frame.exec(jvm)? {
+                    Void => {},
+                    val => jvm.stack.push(val)
+                }
+            },
+            NEW => {
+                let idx = u16::from_be_bytes([self.code.bytecode[self.ip as usize+1], self.code.bytecode[self.ip as usize+2]]);
+                self.ip += 2;
+
+                if let Const::Class(class_idx) = self.class.cp.get(idx) {
+                    let clname = jvm.loader.resolve(&self.class.cp, class_idx as usize);
+                    let object = jvm.alloc_object(&clname)?;
+                    jvm.stack.push(Ref(object))
+                }else{
+                    panic!("Tried instantiating a non-class");
+                }
+            },
+            NEWARRAY => {
+                let typ: ArrayTypes = FromPrimitive::from_u8(self.code.bytecode[self.ip as usize + 1]).unwrap();
+                self.ip += 1;
+
+                let count = self.pop_count(jvm)?;
+                let idx = jvm.alloc_array(ElementType::Primitive(typ), count)?;
+
+                jvm.stack.push(Array(idx));
+            },
+            ANEWARRAY => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+                let component = self.class_name(jvm, idx);
+                let count = self.pop_count(jvm)?;
+                let array = jvm.alloc_array(ElementType::Class(component), count)?;
+                jvm.stack.push(Array(array));
+            },
+            MULTIANEWARRAY => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+                let dims = self.read_bytes::<1>()[0] as usize;
+                let desc = self.class_name(jvm, idx);
+                let mut counts = Vec::with_capacity(dims);
+                for _ in 0..dims{
+                    counts.push(self.pop_count(jvm)?);
+                }
+                counts.reverse();
+                let array = self.new_multi_array(jvm, &desc, &counts)?;
+                jvm.stack.push(Array(array));
+            },
+            CHECKCAST | INSTANCEOF => {
+                let idx = u16::from_be_bytes(self.read_bytes());
+                let target = self.class_name(jvm, idx);
+                // The value stays on the stack while loading `target`, which may run a GC.
+                let val = self.peek(jvm, 0);
+                let is_instance = match jvm.class_of(&val) {
+                    Some(class) => jvm.is_assignable(&class, &target)?,
+                    None => false
+                };
+                if op == INSTANCEOF{
+                    self.pop(jvm);
+                    jvm.stack.push(Int(is_instance as i32));
+                }else if !is_instance && !matches!(val, Null){
+                    let class = jvm.class_of(&val).unwrap_or_default();
+                    let message = format!("class {} cannot be cast to class {}", class.replace('/', "."), target.replace('/', "."));
+                    return Err(jvm.exception("java/lang/ClassCastException", Some(&message)));
+                }
+            },
+            opc => panic!("Unimplemented opcode {:?}", opc)
+        }
+        self.ip += 1;
+        return Ok(None);
+    }
+
+    /// Looks for a handler for `ex`, thrown by the instruction at `pc`, and jumps to it with `ex`
+    /// as the only value on the stack. Hands `ex` back if this method doesn't catch it.
+    fn catch(&mut self, jvm: &mut Jvm, pc: u32, ex: Thrown) -> Result<(), Thrown>{
+        let Thrown::Exception(r) = ex else { return Err(ex) };
+        // Loading a catch type may run a GC, so keep the exception where it can be seen.
+        jvm.stack.truncate(self.stack);
+        jvm.stack.push(Ref(r));
+        let class = jvm.heap.get(r).class.clone();
+        let code = self.code.clone();
+        for handler in &code.exception_table{
+            if pc < handler.start_pc as u32 || pc >= handler.end_pc as u32{
+                continue;
+            }
+            let caught = match &handler.catch_type {
+                Some(catch_type) => jvm.is_assignable(&class, catch_type)?,
+                None => true
+            };
+            if caught{
+                trace!("{} caught at {}", class, handler.handler_pc);
+                self.ip = handler.handler_pc as u32;
+                return Ok(());
+            }
+        }
+        return Err(ex);
+    }
+
+    /// Pops an index and an array reference, checking the reference isn't null and the index is in bounds.
+    fn pop_array_index(&mut self, jvm: &mut Jvm) -> Result<(usize, usize), Thrown>{
+        let idx = self.pop_int(jvm);
+        let array = match self.pop(jvm) {
+            Array(array) => array,
+            Null => return Err(jvm.exception("java/lang/NullPointerException", Some("Cannot access an element because the array is null"))),
+            val => panic!("Expected an array, got {:?}", val)
+        };
+        let len = jvm.heap.array(array).data.len();
+        if idx < 0 || idx as usize >= len{
+            let message = format!("Index {} out of bounds for length {}", idx, len);
+            return Err(jvm.exception("java/lang/ArrayIndexOutOfBoundsException", Some(&message)));
+        }
+        return Ok((array, idx as usize));
+    }
+
+    /// Pops an array length, which must not be negative.
+    fn pop_count(&mut self, jvm: &mut Jvm) -> Result<usize, Thrown>{
+        let count = self.pop_int(jvm);
+        if count < 0{
+            return Err(jvm.exception("java/lang/NegativeArraySizeException", Some(&count.to_string())));
+        }
+        return Ok(count as usize);
+    }
+
+    /// Returns the slot of the instance field `class.name`, throwing `NoSuchFieldError` if there is none.
+    fn field_slot(&self, jvm: &mut Jvm, class: &str, name: &str, desc: &str) -> Result<usize, Thrown>{
+        return match jvm.resolve_class(class)?.field_slot(name, desc) {
+            Some(slot) => Ok(slot),
+            None => Err(jvm.exception("java/lang/NoSuchFieldError", Some(name)))
+        }
+    }
+
+    /// Returns the class declaring the static field `class.name`, which may be inherited from a superclass
+    /// or superinterface, throwing `NoSuchFieldError` if there is none.
+    fn static_field_class(&self, jvm: &mut Jvm, class: &str, name: &str, desc: &str) -> Result<Rc<Class>, Thrown>{
+        return match jvm.resolve_field(class, name, desc)? {
+            Some(declaring) if declaring.static_field(name, desc).is_some() => Ok(declaring),
+            Some(declaring) => {
+                let message = format!("Expected static field {}.{}", declaring.name.replace('/', "."), name);
+                Err(jvm.exception("java/lang/IncompatibleClassChangeError", Some(&message)))
+            },
+            None => Err(jvm.exception("java/lang/NoSuchFieldError", Some(name)))
+        }
+    }
+
+    /// Resolves the `CONSTANT_Class` at `idx` to a class name or array descriptor.
+    fn class_name(&self, jvm: &Jvm, idx: u16) -> String{
+        if let Const::Class(name_idx) = self.class.cp.get(idx){
+            return jvm.loader.resolve(&self.class.cp, name_idx as usize);
+        }
+        panic!("Expected a class constant at {}.", idx);
+    }
+
+    /// Allocates an array of type `desc` (e.g. `[[I`) whose first `counts.len()` dimensions have the given lengths.
+    fn new_multi_array(&mut self, jvm: &mut Jvm, desc: &str, counts: &[usize]) -> Result<usize, Thrown>{
+        let component = &desc[1..];
+        let array = jvm.alloc_array(ElementType::from_descriptor(component), counts[0])?;
+        if counts.len() > 1{
+            // Keep the outer array on the operand stack, where the GC can see it, while filling it.
+            jvm.stack.push(Array(array));
+            for i in 0..counts[0]{
+                let sub = self.new_multi_array(jvm, component, &counts[1..])?;
+                jvm.heap.array_mut(array).data[i] = Array(sub);
+            }
+            jvm.stack.pop();
+        }
+        return Ok(array);
+    }
+
+    /// Adds `inc` to the int in local `idx`.
+    fn iinc(&mut self, jvm: &mut Jvm, idx: usize, inc: i32){
+        let local = &mut jvm.stack[self.locals + idx];
+        if let Int(a) = *local{
+            *local = Int(a.wrapping_add(inc));
+        }else{
+            panic!("Expected an int in local {}.", idx);
+        }
+    }
+
+    /// Reads the signed 16-bit offset of the branch at `pc` and returns its target.
+    fn branch_target(&mut self, pc: u32) -> u32{
+        let offset = i16::from_be_bytes(self.read_bytes());
+        return pc.wrapping_add_signed(offset as i32);
+    }
+
+    /// Pushes local `idx`.
+    fn load(&mut self, jvm: &mut Jvm, idx: usize){
+        let val = jvm.stack[self.locals + idx].clone();
+        jvm.stack.push(val);
+    }
+
+    /// Pops a value into local `idx`. Longs and doubles also take up local `idx + 1`.
+    fn store(&mut self, jvm: &mut Jvm, idx: usize){
+        let val = self.pop(jvm);
+        if val.category() == 2{
+            jvm.stack[self.locals + idx + 1] = Void;
+        }
+        jvm.stack[self.locals + idx] = val;
+    }
+
+    /// Returns how many stack entries, counted from the top, make up `words` operand stack
+    /// words. Longs and doubles are a single entry but two words; a category-2 value can't be split.
+    fn peek_words(&self, jvm: &Jvm, words: usize) -> usize{
+        return self.peek_words_below(jvm, 0, words);
+    }
+
+    /// Like `peek_words`, ignoring the topmost `skip` entries.
+    fn peek_words_below(&self, jvm: &Jvm, skip: usize, words: usize) -> usize{
+        let mut entries = 0;
+        let mut counted = 0;
+        while counted < words{
+            let val = jvm.stack[self.stack..].iter().rev().nth(skip + entries).expect("Stack empty");
+            counted += val.category();
+            entries += 1;
+        }
+        if counted != words{
+            panic!("Can't split a long or double on the stack.");
+        }
+        return entries;
+    }
+
+    /// Copies the topmost `top` entries below the `under` entries beneath them.
+    fn dup_under(&mut self, jvm: &mut Jvm, top: usize, under: usize){
+        let len = jvm.stack.len();
+        let copy: Vec<Types> = jvm.stack[len - top..].to_vec();
+        let at = len - top - under;
+        jvm.stack.splice(at..at, copy);
+    }
+
+    fn pop_int(&mut self, jvm: &mut Jvm) -> i32{
+        return if let Int(i) = self.pop(jvm){i}else{panic!("Expected i32 on the stack.")};
+    }
+
+    fn pop_long(&mut self, jvm: &mut Jvm) -> i64{
+        return if let Long(l) = self.pop(jvm){l}else{panic!("Expected i64 on the stack.")}
+    }
+
+    /// Pops two ints and pushes `f` applied to them.
+    fn int_op(&mut self, jvm: &mut Jvm, f: fn(i32, i32) -> i32){
+        let b = self.pop_int(jvm);
+        let a = self.pop_int(jvm);
+        jvm.stack.push(Int(f(a, b)));
+    }
+
+    /// Pops two longs and pushes `f` applied to them.
+    fn long_op(&mut self, jvm: &mut Jvm, f: fn(i64, i64) -> i64){
+        let b = self.pop_long(jvm);
+        let a = self.pop_long(jvm);
+        jvm.stack.push(Long(f(a, b)));
+    }
+
+    fn pop_double(&mut self, jvm: &mut Jvm) -> f64{
+        return if let Double(f) = self.pop(jvm){f}else{panic!("Expected double on the stack")}
+    }
+
+    fn pop_float(&mut self, jvm: &mut Jvm) -> f32{
+        return if let Float(f) = self.pop(jvm){f}else{panic!("Expected float on the stack")}
+    }
+
+    /// Pops two floats and pushes `f` applied to them.
+    fn float_op(&mut self, jvm: &mut Jvm, f: fn(f32, f32) -> f32){
+        let b = self.pop_float(jvm);
+        let a = self.pop_float(jvm);
+        jvm.stack.push(Float(f(a, b)));
+    }
+
+    /// Pops two doubles and pushes `f` applied to them.
+    fn double_op(&mut self, jvm: &mut Jvm, f: fn(f64, f64) -> f64){
+        let b = self.pop_double(jvm);
+        let a = self.pop_double(jvm);
+        jvm.stack.push(Double(f(a, b)));
+    }
+
+    fn handle_fmi(&self, jvm: &Jvm, fmi_ref: Const) -> (String, String, String){
+        if let Const::FMIRef((class_idx, nat_idx)) | Const::InterfaceMethodRef((class_idx, nat_idx)) = fmi_ref{
+            let nat = self.class.cp.get(nat_idx);
+            if let Const::NameAndType((name_idx, type_idx)) = nat{
+                let class = self.class.cp.get(class_idx);
+                if let Const::Class(clname_idx) = class {
+                    let clname = jvm.loader.resolve(&self.class.cp, clname_idx as usize);
+                    let name = jvm.loader.resolve(&self.class.cp, name_idx as usize);
+                    let typ = jvm.loader.resolve(&self.class.cp, type_idx as usize);
+
+                    return (clname, name, typ);
+                }else{
+                    panic!("Expected Class.");
+                }
+            }else{
+                panic!("Expected NAT.");
+            }
+        }else{
+            panic!("Expected a field, method or interface method ref.");
+        }
+    }
+
+    /// Reads the big-endian int at offset `at` of the code.
+    fn i32_at(&self, at: u32) -> i32{
+        let at = at as usize;
+        return i32::from_be_bytes(self.code.bytecode[at..at + 4].try_into().unwrap());
+    }
+
+    fn read_bytes<const T: usize>(&mut self) -> [u8; T]{
+        let mut r = [0u8; T];
+        for b in r.iter_mut(){
+            *b = self.code.bytecode[self.ip as usize + 1];
+            self.ip += 1;
+        }
+        return r;
+    }
+}
@@ -1,212 +1,115 @@
-use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
 use crate::{Class};
-use crate::types::{Attribute, Const, ConstPool, Field, MethodAccessFlags};
-
-pub struct Loader{
-    pub(crate) r: Option<File>,
-    pub(crate) loaded_classes: Option<HashMap<String, Class>>
+use crate::classpath::ClassPath;
+use crate::parser::ClassFormatError;
+use crate::types::{Const, ConstPool, FieldAccessFlags, FieldSlot, Types};
+
+/// Reasons a class could not be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError{
+    NotFound(String),
+    Format(ClassFormatError),
+    /// The class is its own superclass, directly or indirectly.
+    Circularity(String),
+    /// A class with this name has already been defined.
+    Duplicate(String),
+    /// The static initializer of the class failed before.
+    Erroneous(String),
+    /// The static initializer threw the described exception.
+    Initializer(String),
+    /// The static initializer called `System.exit` with this status.
+    Exit(i32)
 }
 
-impl Loader{
-    pub fn u1(&mut self) -> u8{
-        return self.bytes::<1>()[0];
-    }
-
-    pub fn u2(&mut self) -> u16 {
-        return u16::from_be_bytes(self.bytes());
-    }
-
-    pub fn u4(&mut self) -> u32{
-        return u32::from_be_bytes(self.bytes());
+impl LoadError{
+    /// The Java exception class this error is reported as.
+    pub fn java_class(&self) -> &'static str{
+        return match self {
+            LoadError::NotFound(_) => "java/lang/NoClassDefFoundError",
+            LoadError::Format(e) => e.java_class(),
+            LoadError::Circularity(_) => "java/lang/ClassCircularityError",
+            LoadError::Duplicate(_) => "java/lang/LinkageError",
+            LoadError::Erroneous(_) => "java/lang/NoClassDefFoundError",
+            LoadError::Initializer(_) => "java/lang/ExceptionInInitializerError",
+            // Never thrown: the VM unwinds instead.
+            LoadError::Exit(_) => "java/lang/Error"
+        }
     }
+}
 
-    pub fn bytes<const N: usize>(&mut self) -> [u8; N]{
-        let mut buf = [0 as u8; N];
-        self.r.as_ref().unwrap().read_exact(&mut buf[..]).unwrap();
-        return buf;
+impl fmt::Display for LoadError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        return match self {
+            LoadError::NotFound(name) => write!(f, "{}", name),
+            LoadError::Format(e) => write!(f, "{}", e),
+            LoadError::Circularity(name) => write!(f, "{}", name),
+            LoadError::Duplicate(name) => write!(f, "Attempted duplicate class definition for {}", name),
+            LoadError::Erroneous(name) => write!(f, "Could not initialize class {}", name),
+            LoadError::Initializer(e) => write!(f, "{}", e),
+            LoadError::Exit(status) => write!(f, "Exited with status {}", status)
+        }
     }
+}
 
-    pub fn vec_bytes(&mut self, n: usize) -> Vec<u8>{
-        let mut vec = Vec::with_capacity(n);
-        vec.resize(n, 0);
-        self.r.as_ref().unwrap().read_exact(&mut vec).unwrap();
-        return vec;
+impl From<ClassFormatError> for LoadError{
+    fn from(e: ClassFormatError) -> Self{
+        LoadError::Format(e)
     }
+}
 
-    pub fn vec_bytes_u4(&mut self) -> Vec<u8>{
-        let num = self.u4() as usize;
-        return self.vec_bytes(num);
-    }
+pub struct Loader{
+    pub(crate) loaded_classes: HashMap<String, Rc<Class>>,
+    /// Classes whose superclass is being loaded.
+    pub(crate) loading: HashSet<String>,
+    /// Classes whose static initializer failed. They stay defined, but can't be used.
+    pub(crate) erroneous: HashSet<String>,
+    pub(crate) classpath: ClassPath
+}
 
-    pub fn vec_bytes_u2(&mut self) -> Vec<u8>{
-        let num = self.u2() as usize;
-        return self.vec_bytes(num);
+impl Loader{
+    pub fn new(classpath: ClassPath) -> Self{
+        Loader{loaded_classes: HashMap::new(), loading: HashSet::new(), erroneous: HashSet::new(), classpath}
     }
 
     pub(crate) fn resolve(&self, cp: &ConstPool, i: usize) -> String{
         return match &cp.consts[i - 1] {
-            Const::Str(s) => s.clone(),
+            Const::Str(s, _) => s.clone(),
             Const::Class(idx) => self.resolve(cp, *idx as usize),
-            _ => {println!("Ritorno stringa vuota");String::new()}
+            _ => {trace!("Ritorno stringa vuota");String::new()}
         }
     }
 
-    fn resolve_u2(&mut self, cp: &mut ConstPool) -> String{
-        let idx = self.u2() as usize;
-        return self.resolve(cp, idx);
-    }
-
-    fn resolve_super(&mut self, cp: &mut ConstPool) -> String{
-        let idx = self.u2();
-        return if idx == 0 {
-            "".to_string()
-        } else {
-            self.resolve(cp, idx as usize)
-        }
+    pub fn get(&self, name: &str) -> Option<Rc<Class>>{
+        return self.loaded_classes.get(name).cloned();
     }
 
-    fn cpinfo(&mut self) -> ConstPool{
-        let mut cp = ConstPool{consts: Vec::new()};
-        let cp_count = self.u2();
-        println!("Constants: {}", cp_count);
-        let mut long_or_double = false;
-        for _ in 1..cp_count {
-            if long_or_double {
-                long_or_double = false;
-                cp.consts.push(Const::Invalid);
-                continue;
-            }
-            let tag = self.u1();
-            let c: Const;
-            match tag{
-                0x01 => c = Const::Str(String::from_utf8(self.vec_bytes_u2()).unwrap()),
-                0x03 => c = Const::Int(i32::from_be_bytes(self.bytes())),
-                0x04 => c = Const::Float(f32::from_be_bytes(self.bytes())),
-                0x06 => c = { long_or_double = true; Const::Double(f64::from_be_bytes(self.bytes()))},
-                0x07 => c = Const::Class(self.u2()),
-                0x08 => c = Const::StrIndex(self.u2()),
-                0x09|0xa => c = Const::FMIRef((self.u2(), self.u2())),
-                0x0c => c = Const::NameAndType((self.u2(), self.u2())),
-                _all => {println!("Error parsing tag {}: Not implemented!", _all); continue;}
-            }
-            cp.consts.push(c);
-
-        }
-        return cp;
+    /// Reads the class file for `name` from the class path.
+    pub fn find(&mut self, name: &str) -> Result<Vec<u8>, LoadError>{
+        return self.classpath.find(name).ok_or_else(|| LoadError::NotFound(name.replace('/', ".")));
     }
 
-    fn interfaces(&mut self, cp: &mut ConstPool) -> Vec<String>{
-        let mut v: Vec<String> = Vec::new();
-        let interface_count = self.u2();
-        for _ in 0..interface_count{
-            v.push(self.resolve_u2(cp));
+    /// Links a parsed class against its already loaded superclass and registers it,
+    /// unless a class with the same name was defined before.
+    pub fn define(&mut self, mut c: Class, supr: Option<&Class>) -> Result<Rc<Class>, LoadError>{
+        if self.loaded_classes.contains_key(&c.name){
+            return Err(LoadError::Duplicate(c.name.replace('/', ".")));
         }
-        return v;
-    }
-
-    fn fields(&mut self, cp: &mut ConstPool) -> Vec<Field>{
-        let mut v: Vec<Field> = Vec::new();
-        let field_count = self.u2();
-        for _ in 0..field_count{
-            v.push(Field{
-                flags: self.u2(),
-                name: self.resolve_u2(cp),
-                desc: self.resolve_u2(cp),
-                attr: self.attributes(cp),
-                value: None
-            });
-        }
-        return v;
-    }
-
-    fn attributes(&mut self, cp: &mut ConstPool) -> Vec<Attribute>{
-        let mut v: Vec<Attribute> = Vec::new();
-        let attr_count = self.u2();
-
-        for _ in 0..attr_count{
-            v.push(Attribute{
-                name: self.resolve_u2(cp),
-                data: self.vec_bytes_u4()
-            })
-        }
-        return v;
-    }
-
-    pub fn load_class(&mut self, f: Option<File>) -> String{
-
-        if let Some(file) = f{
-            self.r = Some(file);
-        }
-
-        assert_eq!(0xcafebabeu32, self.u4());
-        let version = [self.u2(), self.u2()];
-        println!("Java version: {:?}", version);
-
-        let mut cp = self.cpinfo();
-        let flags = self.u2();
-        let name = self.resolve_u2(&mut cp);
-        let supr = self.resolve_super(&mut cp);
-        let interfaces = self.interfaces(&mut cp);
-        let fields = self.fields(&mut cp);
-        let methods = self.fields(&mut cp);
-        let attributes = self. attributes(&mut cp);
-
-        let c = Class{
-            cp,
-            flags,
-            name: name.clone(),
-            supr,
-            interfaces,
-            fields,
-            methods,
-            attributes,
-            version
-        };
-
-        self.loaded_classes.as_mut().unwrap().insert(name.clone(), c);
-
-        let c = self.loaded_classes.as_mut().unwrap().get_mut(name.clone().as_str()).unwrap();
-
-        for m in &c.methods{
-            if m.name == "<clinit>" && m.desc == "()V"{
-                if version[0] >= 51{
-                    let flags = MethodAccessFlags::new(m.flags);
-                    if !flags.STATIC{ continue; }
-                }
-
-                c.frame(m.name.clone(), m.desc.clone(), Vec::new()).exec();
-                break;
+        // Instance fields are laid out after the ones inherited from the superclass,
+        // so a slot index stays valid for every subclass.
+        let mut layout = supr.map(|s| s.layout.clone()).unwrap_or_default();
+        for f in &c.fields{
+            if FieldAccessFlags::new(f.flags).STATIC{
+                *f.value.borrow_mut() = Some(Types::default_for(&f.desc));
+            }else{
+                layout.push(FieldSlot{class: c.name.clone(), name: f.name.clone(), desc: f.desc.clone()});
             }
         }
-        return name;
-    }
+        c.layout = layout;
 
-    pub fn get_class(&mut self, name: String) -> &mut Class{
-
-        println!("Fetching class {}..", name);
-
-        let paths = vec!(Path::new("./"), Path::new("./src/"));
-
-        let result = self.loaded_classes.as_mut().unwrap().get(&name);
-        return match result {
-            None => {
-                let mut clname = String::new();
-                for path in paths {
-                    match File::open(path.join(Path::new(&(name.clone()+".class")))) {
-                        Ok(file) => {clname = self.load_class(Some(file)); break;},
-                        Err(_) => continue
-                    }
-                }
-                if clname.is_empty(){
-                    panic!("NoSuchMethod");
-                }
-                self.loaded_classes.as_mut().unwrap().get_mut(&clname).unwrap()
-            }
-            Some(_) => { self.loaded_classes.as_mut().unwrap().get_mut(&name).unwrap() }
-        };
+        let c = Rc::new(c);
+        self.loaded_classes.insert(c.name.clone(), c.clone());
+        return Ok(c);
     }
-}
\ No newline at end of file
+}
@@ -1,509 +1,125 @@
-#![allow(dead_code)]
-
-extern crate core;
-
-use std::borrow::BorrowMut;
-use std::fs::{File};
-use std::collections::HashMap;
-use num_traits::FromPrimitive;
-use opcodes::Opcodes::*;
-use crate::types::{ArrayTypes, Attribute, Const, ConstPool, Field, MethodAccessFlags, Types};
-use crate::loader::Loader;
-use crate::opcodes::Opcodes;
-use crate::Types::*;
-
-mod opcodes;
-mod types;
-pub mod loader;
-mod natives;
-
-static mut L: Loader = Loader{r: None, loaded_classes: None};
-
-
-pub struct Frame<'a>{
-    class: &'a mut Class,
-    ip: u32,
-    code: Vec<u8>,
-    locals: Vec<Types>,
-    stack: Vec<Types>,
-    arrays: Vec<Vec<Types>>,
-    native: bool,
-    native_fn: Option<&'a fn(&crate::Class, Vec<Types>) -> Types>
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use jvm::{launcher, Jvm, JvmError, VERBOSE};
+use jvm::classpath::ClassPath;
+use jvm::loader::LoadError;
+
+/// Environment variable locating the class library when `-Xbootclasspath` isn't given.
+const BOOT_CLASSPATH_VAR: &str = "JVM_BOOT_CLASSPATH";
+
+/// The class library in the checkout a development build was made from.
+#[cfg(debug_assertions)]
+const DEV_BOOT_CLASSPATH: Option<&str> = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/src"));
+#[cfg(not(debug_assertions))]
+const DEV_BOOT_CLASSPATH: Option<&str> = None;
+
+/// Stack size of the thread running Java code; every Java call nests `Frame::exec` on it,
+/// so it must hold `MAIN_STACK_DEPTH` interpreter frames.
+const MAIN_STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Maximum number of nested Java calls on the main thread.
+const MAIN_STACK_DEPTH: usize = 2048;
+
+/// Locates the class library: named by `JVM_BOOT_CLASSPATH`, installed in the `lib` directory
+/// next to the one holding the executable (like a JDK's `bin` and `lib`), or, for development
+/// builds, in their checkout.
+fn default_boot_classpath() -> Option<String>{
+    if let Ok(path) = std::env::var(BOOT_CLASSPATH_VAR){
+        return Some(path);
+    }
+    let installed = std::env::current_exe().ok()
+        .and_then(|exe| Some(exe.parent()?.parent()?.join("lib")))
+        .filter(|lib| lib.join("java/lang/Object.class").is_file());
+    if let Some(lib) = installed{
+        return Some(lib.to_string_lossy().into_owned());
+    }
+    DEV_BOOT_CLASSPATH.map(str::to_string)
 }
 
-#[derive(Debug, Clone)]
-pub struct Class{
-    cp: ConstPool,
-    name: String,
-    supr: String,
-    flags: u16,
-    interfaces: Vec<String>,
-    fields: Vec<Field>,
-    methods: Vec<Field>,
-    attributes: Vec<Attribute>,
-    version: [u16; 2]
+/// Prints a launcher error and exits like `java` does.
+fn fail(msg: &str) -> ! {
+    eprintln!("Error: {}", msg);
+    std::process::exit(1);
 }
 
-impl Class{
-    pub fn frame(&mut self, method: String, desc: String, args: Vec<Types>) -> Frame{
-        println!("Loading method {}::{} with locals {:?}",self.name, method, args);
-        for m in &self.methods{
-            if m.name == method && m.desc == desc{
-                if MethodAccessFlags::new(m.flags).NATIVE{
-                    println!("Loading native...");
-                    unsafe {
-                        if let Some(n) = &mut natives::NATIVES {
-                            return Frame {
-                                native_fn: Some(n.get(&(self.name.clone(), m.name.clone(), m.desc.clone())).unwrap()),
-                                class: self,
-                                ip: 0,
-                                code: vec![],
-                                locals: args,
-                                stack: vec![],
-                                arrays: vec![],
-                                native: true,
-                            }
-                        }
-                    }
-                }
-                for a in &m.attr{
-                    if a.name == "Code" && a.data.len() > 8{
-                        let max_locals = u16::from_be_bytes([a.data[2],a.data[3]]);
-                        println!("max locals: {}", max_locals);
-                        let mut frame = Frame{
-                            ip: 0,
-                            code: a.data[8..].to_vec(),
-                            locals: Vec::with_capacity(max_locals as usize),
-                            stack: Vec::new(),
-                            class: self,
-                            arrays: Vec::new(),
-                            native: false,
-                            native_fn: None
-                        };
-                        frame.locals.resize(max_locals.into(), Int(0));
-                        for (i, item) in args.iter().enumerate(){
-                            frame.locals[i] = item.clone();
-                        }
-                        return frame;
-                    }
-                }
-            }
-        }
-        panic!("Method {}:{} not found in class {}!", method, desc, self.name);
+fn main() {
+    let main_thread = std::thread::Builder::new()
+        .name("main".to_string())
+        .stack_size(MAIN_STACK_SIZE)
+        .spawn(launch)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    if main_thread.join().is_err(){
+        std::process::exit(1);
     }
 }
 
-impl Frame<'_>{
-    pub fn pop(&mut self) -> Types{
-        return self.stack.pop().expect("Stack empty");
-    }
-
-    pub fn exec(&mut self) -> Types{
-
-        if self.native{
-            return self.native_fn.unwrap()(self.class, self.locals.clone());
-        }
-
-        loop{
-            let op: Opcodes = FromPrimitive::from_u8(self.code[self.ip as usize]).expect("Invalid opcode.");
-            println!{"Executing opcode {:?} ({}) with stack {:?}", op, op as u8, self.stack};
-
-            match op{
-                NOP => {}
-                ICONST_M1 => self.stack.push(Int(-1)),
-                ICONST_0 => self.stack.push(Int(0)),
-                ICONST_1 => self.stack.push(Int(1)),
-                ICONST_2 => self.stack.push(Int(2)),
-                ICONST_3 => self.stack.push(Int(3)),
-                ICONST_4 => self.stack.push(Int(4)),
-                ICONST_5 => self.stack.push(Int(5)),
-                LCONST_0 => self.stack.push(Long(0)),
-                LCONST_1 => self.stack.push(Long(1)),
-                BIPUSH => {
-                    self.ip = self.ip +1;
-                    let val = self.code[self.ip as usize];
-                    self.stack.push(Int(val as i32))
-                },
-                ILOAD_0 | DLOAD_0 | ALOAD_0 => self.stack.push(self.locals[0].clone()),
-                ILOAD_1 | DLOAD_1 | ALOAD_1 => self.stack.push(self.locals[1].clone()),
-                ILOAD_2 | DLOAD_2 | ALOAD_2 => self.stack.push(self.locals[2].clone()),
-                ILOAD_3 | DLOAD_3 | ALOAD_3 => self.stack.push(self.locals[3].clone()),
-                ASTORE_0 | ISTORE_0 => self.locals[0] = self.pop(),
-                ASTORE_1 | ISTORE_1 => self.locals[1] = self.pop(),
-                ASTORE_2 | ISTORE_2 => self.locals[2] = self.pop(),
-                ASTORE_3 | ISTORE_3 => self.locals[3] = self.pop(),
-                IASTORE => {
-                    let val = self.pop();
-                    let idx = self.pop_int() as usize;
-                    let array_ref = self.pop();
-                    if let Array((a_idx, typ)) = array_ref{
-                        self.arrays[a_idx][idx] = val;
-                    }else{
-                        panic!("Invalid Array.");
-                    }
-                },
-                IALOAD => {
-                    let idx = self.pop_int() as usize;
-                    let array_ref = self.pop();
-
-                    if let Array((a_idx, typ)) = array_ref{
-                        self.stack.push(self.arrays[a_idx][idx].clone());
-                    }else{
-                        panic!("Invalid array.");
-                    }
-                },
-                POP => {self.stack.pop();},
-                POP2 => {
-                    let val = self.pop();
-                    match val{
-                        Long(_) | Double(_) => {},
-                        _ => {self.pop();},
-                    }
-                },
-                DUP => {
-                    let val = self.pop();
-                    self.stack.push(val.clone());
-                    self.stack.push(val);
-                },
-                IADD => {
-                    let b = self.pop_int();
-                    let a = self.pop_int();
-                    self.stack.push(Int(a+b));
-                },
-                ISUB => {
-                    let a = self.pop_int();
-                    let b = self.pop_int();
-                    self.stack.push(Int(b-a))
-                },
-                DADD => {
-                    let a = self.pop_double();
-                    let b = self.pop_double();
-                    self.stack.push(Double(a+b));
-                },
-                DSUB => {
-                    let a = self.pop_double();
-                    let b = self.pop_double();
-                    self.stack.push(Double(b-a));
-                },
-                LDC => unsafe{
-                    let idx = self.code[self.ip as usize + 1];
-                    self.ip += 1;
-
-                    match self.class.cp.get(idx as u16) {
-                        Const::Str(s) => { self.stack.push(Str(s)) }
-                        Const::Int(i) => { self.stack.push(Int(i)) }
-                        Const::Float(f) => { self.stack.push(Float(f)) }
-                        Const::Class(name_idx) => {
-                            let class = L.get_class(L.resolve(&self.class.cp, name_idx as usize));
-                            self.stack.push(Class(class.name.clone()));
-                        }
-                        Const::FMIRef(fmi) => {
-                            let (clname, name, desc) = self.handle_fmi(Const::FMIRef(fmi));
-                            unimplemented!()
-                        }
-                        _ => panic!()
-                    }
-                }
-                LDC2_W => {
-                    let idx = u16::from_be_bytes(self.read_bytes());
-                    let val = &self.class.cp.consts[idx as usize - 1];
-                    println!("{:?}", val);
-                    if let Const::Double(i) = val{
-                        self.stack.push(Double(*i))
-                    }else if let Const::Long(l) = val{
-                        self.stack.push(Long(*l))
-                    }else{
-                        println!("Called LDC2_W on a NON-LONG! Ignoring...");
-                    }
-                },
-                IFGE => {
-                    let offset = u16::from_be_bytes(self.read_bytes());
-                    let a = self.pop_int();
-                    if a >= 0{
-                        self.ip += offset as u32 - 1;
-                    }else{
-                        self.ip += 2;
-                    }
-                },
-                IF_ICMPNE => {
-                    let offset = u16::from_be_bytes(self.read_bytes());
-                    let a = self.pop_int();
-                    let b = self.pop_int();
-                    if a != b{
-                        self.ip += offset as u32 - 1;
-                    }else{
-                        self.ip += 2;
-                    }
-                },
-                IRETURN | DRETURN | LRETURN | ARETURN | FRETURN => return self.pop(),
-                RETURN => return Void,
-                GETFIELD => unsafe {
-                    let idx = u16::from_be_bytes(self.read_bytes());
-
-                    let field = self.class.cp.get(idx);
-                    let (clname, fname, fdesc) = self.handle_fmi(field);
-                    let cl = L.get_class(clname.clone());
-                    println!("{:?}", cl.fields);for field in &mut cl.fields{
-                        if field.name == fname && field.desc == fdesc{
-                            println!("{}::{}-{} = {:?}", clname, fname, fdesc, field.value.clone());
-                            self.stack.push(field.value.borrow_mut().clone().unwrap());
-                            break;
-                        }
-                    }
-                }
-                PUTFIELD => unsafe{
-                    let idx = u16::from_be_bytes([self.code[self.ip as usize+1], self.code[self.ip as usize+2]]);
-                    self.ip = self.ip + 2;
-
-                    let field = self.class.cp.get(idx);
-                    if let Const::FMIRef((class_idx, nat_idx)) = field{
-                        let nat = self.class.cp.get(nat_idx);
-                        if let Const::NameAndType((name_idx, typ_idx)) = nat {
-                            if let Const::Class(clname_idx) = self.class.cp.get(class_idx){
-                                let c = L.get_class(L.resolve(&mut self.class.cp, clname_idx as usize));
-                                let value = self.pop();
-                                if let Types::Class(name) = self.pop(){
-                                    let cl = L.get_class(name);
-                                    for field in &mut cl.fields{
-                                        if field.name == L.resolve(&mut self.class.cp, name_idx as usize) && field.desc == L.resolve(&mut self.class.cp, typ_idx as usize){
-                                            field.value = Some(value);
-                                            break;
-                                        }
-                                    }
-                                }
-                            }else{
-                                panic!("Corrupted Class");
-                            }
-                        }else{
-                            panic!("Corrupted Method Constant.");
-                        }
-                    }else{
-                        panic!("Tried to invoke a non-method constant");
-                    }
-                },
-                GETSTATIC => unsafe{ //TODO: Properly initialise values.
-                    let idx = u16::from_be_bytes(self.read_bytes());
-
-                    let field_ref = self.class.cp.get(idx);
-                    let (clname, fname, ftype) = self.handle_fmi(field_ref);
-                    println!("{}: {}-{}", clname, fname, ftype);
-                    let class = L.get_class(clname);
-                    for field in &class.fields{
-                        if field.name == fname && field.desc == ftype{
-                            println!("{}, {}-{} : {:?}", self.class.name, field.name, field.desc, field.value);
-                            self.stack.push(field.clone().value.unwrap_or(Void));
-                            break;
-
-                        }
-                    }
-                },
-                PUTSTATIC => unsafe{
-                    let idx = u16::from_be_bytes(self.read_bytes());
-                    let (clname, fname, ftype) = self.handle_fmi(self.class.cp.get(idx));
-                    let cl = L.get_class(clname.clone());
-                    for field in &mut cl.fields{
-                        if field.name == fname && field.desc == ftype{
-                            let val = self.pop();
-                            println!("{}::{} set to {:?}", clname, fname, val);
-                            field.value = Some(val);
-                            break;
-                        }
-                    }
-                },
-                INVOKEVIRTUAL => unsafe {
-                    let idx = u16::from_be_bytes([self.code[self.ip as usize+1], self.code[self.ip as usize+2]]);
-                    self.ip = self.ip + 2;
-
-                    let method = self.class.cp.get(idx);
-                    let (clname, mname, typ) = self.handle_fmi(method);
-                    println!("Resolving class {}..", clname);
-                    let c = L.get_class(clname);
-
-                    let mut v: Vec<Types> = Vec::new();
-                    for ch in typ.chars(){
-                        match ch{
-                            'I' => v.push(Int(self.pop_int())),
-                            ')' => break,
-                            _ => {}
-                        }
-                    }
-                    v.push(self.pop());
-                    v.reverse();
-                    let mut frame = c.frame(mname, typ, v);
-                    match frame.exec() {
-                        Void => {},
-                        val => self.stack.push(val)
-                    }
-                },
-                INVOKESPECIAL => unsafe {
-                    let idx = u16::from_be_bytes([self.code[self.ip as usize+1], self.code[self.ip as usize+2]]);
-                    self.ip = self.ip + 2;
-
-                    let method = self.class.cp.get(idx);
-                    if let Const::FMIRef((class_idx, nat_idx)) = method{
-                        let nat = self.class.cp.get(nat_idx);
-                        if let Const::NameAndType((name_idx, typ_idx)) = nat {
-                            if let Const::Class(clname_idx) = self.class.cp.get(class_idx){
-
-                                let clname = L.resolve(&mut self.class.cp, clname_idx as usize);
-                                println!("Resolving class {}..", clname);
-                                let c = L.get_class(clname);
-
-                                let mut v: Vec<Types> = Vec::new();
-                                let typ = L.resolve(&mut self.class.cp, typ_idx as usize);
-                                for ch in typ.chars(){
-                                    match ch{
-                                        'I' => v.push(Int(self.pop_int())),
-                                        ')' => break,
-                                        _ => {}
-                                    }
-                                }
-                                v.push(self.pop());
-                                v.reverse();
-                                let mut frame = c.frame(L.resolve(&mut self.class.cp, name_idx as usize), typ, v);
-                                match frame.exec() {
-                                    Void => {},
-                                    val => self.stack.push(val)
-                                }
-                            }else{
-                                panic!("Corrupted Class");
-                            }
-                        }else{
-                            panic!("Corrupted Method Constant.");
-                        }
-                    }else{
-                        panic!("Tried to invoke a non-method constant");
-                    }
-                },
-                INVOKESTATIC => unsafe {
-                    let idx = u16::from_be_bytes([self.code[self.ip as usize+1], self.code[self.ip as usize+2]]);
-                    self.ip = self.ip + 2;
-
-                    let method = self.class.cp.get(idx);
-                    if let Const::FMIRef((class_idx, nat_idx)) = method{
-                        let nat = self.class.cp.get(nat_idx);
-                        if let Const::NameAndType((name_idx, typ_idx)) = nat {
-                            if let Const::Class(clname_idx) = self.class.cp.get(class_idx){
-                                //println!("{}, {}, {}", name_idx, typ_idx, clname_idx);
-                                //println!("{} {}, {}", self.class.cp.resolve(name), self.class.cp.resolve(typ), self.class.cp.resolve(clname));
-
-                                let clname = L.resolve(&mut self.class.cp, clname_idx as usize);
-                                let c = L.get_class(clname);
-
-                                let mut v: Vec<Types> = Vec::new();
-                                let typ = L.resolve(&mut self.class.cp, typ_idx as usize);
-                                for ch in typ.chars(){
-                                    match ch{
-                                        'I' => v.push(Int(self.pop_int())),
-                                        'D' => { v.push(Void);v.push(self.pop());},
-                                        ')' => break,
-                                        _ => {}
-                                    }
-                                }
-                                v.reverse();
-                                let mut frame = c.frame(L.resolve(&mut self.class.cp, name_idx as usize), typ,v);
-                                match frame.exec() {
-                                    Void => {},
-                                    val => self.stack.push(val)
-                                }
-                            }else{
-                                panic!("Corrupted Class");
-                            }
-                        }else{
-                            panic!("Corrupted Method Constant.");
-                        }
-                    }else{
-                        panic!("Tried to invoke a non-method constant");
-                    }
-                },
-                NEW => unsafe {
-                    let idx = u16::from_be_bytes([self.code[self.ip as usize+1], self.code[self.ip as usize+2]]);
-                    self.ip = self.ip + 2;
-
-                    println!("...test");
-                    if let Const::Class(class_idx) = self.class.cp.get(idx) {
-                        let class = L.get_class(L.resolve(&mut self.class.cp, class_idx as usize));
-                        self.stack.push(Types::Class(class.name.clone()))
-                    }else{
-                        panic!("Tried instantiating a non-class");
-                    }
-                },
-                NEWARRAY => {
-                    let typ: ArrayTypes = FromPrimitive::from_u8(self.code[self.ip as usize + 1]).unwrap();
-                    self.ip += 1;
-
-                    let count = self.pop_int();
-                    let mut v = Vec::<Types>::with_capacity(count as usize);
-                    v.resize(count as usize, Void);
-
-                    self.arrays.push(v);
-                    let idx = self.arrays.len() - 1;
-
-                    self.stack.push(Array((idx, typ)));
-                }
-                opc => panic!("Unimplemented opcode {:?}", opc)
+fn launch() {
+    let opts = match launcher::parse_args(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            if !msg.is_empty(){
+                eprintln!("Error: {}", msg);
             }
-            self.ip = self.ip + 1;
+            eprintln!("{}", launcher::USAGE);
+            std::process::exit(1);
         }
+    };
+    VERBOSE.store(opts.verbose, Ordering::Relaxed);
+
+    let (user_classpath, main_class) = match &opts.jar {
+        Some(jar) => (jar.clone(), launcher::jar_main_class(Path::new(jar)).unwrap_or_else(|e| fail(&e))),
+        None => (
+            opts.classpath.clone().or_else(|| std::env::var("CLASSPATH").ok()).unwrap_or_else(|| ".".to_string()),
+            opts.main_class.clone().unwrap()
+        )
+    };
+
+    // The class library comes first, so user classes can't shadow it.
+    let boot_classpath = opts.boot_classpath.clone().or_else(default_boot_classpath).unwrap_or_else(||
+        fail(&format!("Could not find the class library; set {} or use -Xbootclasspath", BOOT_CLASSPATH_VAR)));
+    let mut classpath = ClassPath::parse(&boot_classpath).unwrap_or_else(|e| fail(&e.to_string()));
+    if classpath.find("java/lang/Object").is_none(){
+        fail(&format!("Could not find the class library in {}", boot_classpath));
     }
+    let mut user_entries = ClassPath::parse(&user_classpath).unwrap_or_else(|e| fail(&e.to_string()));
+    classpath.entries.append(&mut user_entries.entries);
 
-    fn pop_int(&mut self) -> i32{
-        return if let Int(i) = self.stack.pop().expect("Stack empty"){i}else{panic!("Expected i32 on the stack.")};
-    }
-
-    fn pop_double(&mut self) -> f64{
-        return if let Double(f) = self.pop(){f}else{panic!("Expected double on the stack")}
-    }
-
-    fn handle_fmi(&self, fmi_ref: Const) -> (String, String, String){
-        if let Const::FMIRef((class_idx, nat_idx)) = fmi_ref{
-            let nat = self.class.cp.get(nat_idx);
-            if let Const::NameAndType((name_idx, type_idx)) = nat{
-                let class = self.class.cp.get(class_idx);
-                if let Const::Class(clname_idx) = class {
-                    unsafe{
-                        let clname = L.resolve(&self.class.cp, clname_idx as usize);
-                        let name = L.resolve(&self.class.cp, name_idx as usize);
-                        let typ = L.resolve(&self.class.cp, type_idx as usize);
-
-                        return (clname, name, typ);
-                    }
-                }else{
-                    panic!("Expected Class.");
-                }
-            }else{
-                panic!("Expected NAT.");
-            }
-        }else{
-            panic!("Expected FMI ref.");
-        }
+    let mut jvm = Jvm::new(classpath);
+    jvm.set_max_stack_depth(MAIN_STACK_DEPTH);
+    if let Some(limit) = opts.heap_limit{
+        jvm.set_heap_limit(limit);
     }
-
-    fn read_bytes<const T: usize>(&mut self) -> [u8; T]{
-        let mut r = [0u8; T];
-        for i in 0..T{
-            r[i] = self.code[self.ip as usize + 1];
-            self.ip += 1;
-        }
-        return r;
+    jvm.properties.insert("java.class.path".to_string(), user_classpath);
+    for (key, value) in &opts.properties{
+        jvm.properties.insert(key.clone(), value.clone());
     }
-}
 
-fn main() -> std::io::Result<()> {
-    unsafe{
-        L = Loader{
-            r: Some(File::open("Add.class").unwrap()),
-            loaded_classes: Some(HashMap::new())
-        }
+    let c = match jvm.load_class(&main_class) {
+        Ok(c) => c,
+        Err(LoadError::Exit(status)) => std::process::exit(status),
+        Err(e) => fail(&format!("Could not find or load main class {}\nCaused by: {}: {}",
+                                main_class.replace('/', "."), e.java_class().replace('/', "."), e))
+    };
+    if !c.has_method("main", "([Ljava/lang/String;)V"){
+        fail(&format!("Main method not found in class {}, please define the main method as:\n   public static void main(String[] args)",
+                      main_class.replace('/', ".")));
     }
 
-    natives::load_natives();
-
-    let clname = unsafe { L.load_class(None)};
-    let c = unsafe{L.get_class(clname)};
-
-    let mut frame = c.frame("main".to_string(), "([Ljava/lang/String;)V".to_string() ,vec!());
-    frame.exec();
-
-    Ok(())
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let args = jvm.new_string_array(&opts.args)?;
+        jvm.invoke_static(&main_class, "main", "([Ljava/lang/String;)V", vec![args])
+    }));
+    std::process::exit(match result {
+        Ok(Ok(_)) => 0,
+        Ok(Err(JvmError::Exit(status))) => status,
+        Ok(Err(ref e @ JvmError::Exception{ref stack_trace, ..})) => {
+            eprintln!("Exception in thread \"main\" {}", e);
+            for element in stack_trace{
+                eprintln!("\tat {}", element);
+            }
+            1
+        },
+        Ok(Err(e)) => fail(&e.to_string()),
+        Err(_) => 1
+    });
 }
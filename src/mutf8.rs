@@ -0,0 +1,108 @@
+//! The "modified UTF-8" encoding used by `CONSTANT_Utf8` entries (JVMS 4.4.7):
+//! NUL is encoded on two bytes and supplementary characters are stored as a
+//! surrogate pair, each half encoded on three bytes.
+
+/// Decodes modified UTF-8 into UTF-16 code units, or returns `None` if the bytes are malformed.
+pub fn decode_utf16(bytes: &[u8]) -> Option<Vec<u16>>{
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len(){
+        let x = bytes[i] as u16;
+        match x{
+            0x01..=0x7f => {
+                units.push(x);
+                i += 1;
+            },
+            0xc0..=0xdf => {
+                let y = continuation(bytes, i + 1)?;
+                units.push(((x & 0x1f) << 6) | y);
+                i += 2;
+            },
+            0xe0..=0xef => {
+                let y = continuation(bytes, i + 1)?;
+                let z = continuation(bytes, i + 2)?;
+                units.push(((x & 0xf) << 12) | (y << 6) | z);
+                i += 3;
+            },
+            _ => return None
+        }
+    }
+    return Some(units);
+}
+
+fn continuation(bytes: &[u8], i: usize) -> Option<u16>{
+    return match bytes.get(i) {
+        Some(b) if b & 0xc0 == 0x80 => Some((b & 0x3f) as u16),
+        _ => None
+    }
+}
+
+/// Encodes UTF-16 code units as modified UTF-8.
+pub fn encode_utf16(units: &[u16]) -> Vec<u8>{
+    let mut bytes = Vec::with_capacity(units.len());
+    for &u in units{
+        match u{
+            0x01..=0x7f => bytes.push(u as u8),
+            0x00 | 0x80..=0x7ff => {
+                bytes.push(0xc0 | (u >> 6) as u8);
+                bytes.push(0x80 | (u & 0x3f) as u8);
+            },
+            _ => {
+                bytes.push(0xe0 | (u >> 12) as u8);
+                bytes.push(0x80 | ((u >> 6) & 0x3f) as u8);
+                bytes.push(0x80 | (u & 0x3f) as u8);
+            }
+        }
+    }
+    return bytes;
+}
+
+/// Encodes a string as modified UTF-8.
+pub fn encode(s: &str) -> Vec<u8>{
+    return encode_utf16(&s.encode_utf16().collect::<Vec<u16>>());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn ascii(){
+        assert_eq!(encode("Hello"), b"Hello");
+        assert_eq!(decode_utf16(b"Hello"), Some("Hello".encode_utf16().collect()));
+    }
+
+    #[test]
+    fn nul_takes_two_bytes(){
+        assert_eq!(encode("a\0b"), [b'a', 0xc0, 0x80, b'b']);
+        assert_eq!(decode_utf16(&[b'a', 0xc0, 0x80, b'b']), Some(vec![0x61, 0, 0x62]));
+    }
+
+    #[test]
+    fn two_and_three_byte_forms(){
+        assert_eq!(encode("é€"), [0xc3, 0xa9, 0xe2, 0x82, 0xac]);
+        assert_eq!(decode_utf16(&[0xc3, 0xa9, 0xe2, 0x82, 0xac]), Some(vec![0xe9, 0x20ac]));
+    }
+
+    #[test]
+    fn surrogate_pair_is_encoded_half_by_half(){
+        let bytes = [0xed, 0xa0, 0xbd, 0xed, 0xb8, 0x80];
+        assert_eq!(encode("\u{1f600}"), bytes);
+        assert_eq!(decode_utf16(&bytes), Some(vec![0xd83d, 0xde00]));
+    }
+
+    #[test]
+    fn lone_surrogates_round_trip(){
+        for units in [vec![0xd800], vec![0xdc00, 0x41], vec![0x41, 0xdbff]]{
+            assert_eq!(decode_utf16(&encode_utf16(&units)), Some(units));
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_rejected(){
+        // A raw NUL, a four-byte form, truncated sequences and a bad continuation byte.
+        for bytes in [&[0x00][..], &[0xf0, 0x9f, 0x98, 0x80], &[0xc3], &[0xe2, 0x82], &[0xc3, 0x28], &[0x80]]{
+            assert_eq!(decode_utf16(bytes), None, "{:x?}", bytes);
+        }
+    }
+}
@@ -1,21 +1,64 @@
-use std::collections::HashMap;
-use crate::Types;
+use crate::{Class, Jvm, Thrown, Types};
+use crate::heap::ElementType;
 use Types::*;
 
-pub static mut NATIVES: Option<HashMap<(String, String, String), fn(&crate::Class, Vec<Types>) -> Types>> = None;
+/// A native method: receives the VM, its class and its arguments, and returns its result
+/// or the exception it throws.
+pub type NativeFn = fn(&mut Jvm, &Class, Vec<Types>) -> Result<Types, Thrown>;
 
-pub fn load_natives(){
-    unsafe{
-        NATIVES = Some(HashMap::new());
-        if let Some(n) = &mut NATIVES{
-            n.insert(("java/lang/System".to_string() ,"registerNatives".to_string(), "()V".to_string()), register_natives);
-        }
+/// Registers the natives every VM provides.
+pub fn load_natives(jvm: &mut Jvm){
+    jvm.register_native("java/lang/System", "registerNatives", "()V", register_natives);
+    jvm.register_native("java/lang/System", "getProperty", "(Ljava/lang/String;)Ljava/lang/String;", get_property);
+    jvm.register_native("java/lang/System", "exit", "(I)V", exit);
+    jvm.register_native("java/lang/Throwable", "fillInStackTrace", "()Ljava/lang/Throwable;", fill_in_stack_trace);
+    jvm.register_native("java/lang/Throwable", "getStackTrace", "()[Ljava/lang/StackTraceElement;", get_stack_trace);
+}
+
+pub fn register_natives(_: &mut Jvm, c: &Class, _: Vec<Types>) -> Result<Types, Thrown>{
+    for f in &c.fields{
+        trace!("{}", f.name)
+    }
+    Ok(Void)
+}
+
+pub fn get_property(jvm: &mut Jvm, _: &Class, args: Vec<Types>) -> Result<Types, Thrown>{
+    return match &args[0] {
+        Ref(key) => {
+            let key = jvm.java_string(*key);
+            match jvm.properties.get(&key).cloned() {
+                Some(value) => Ok(Ref(jvm.alloc_string(&value, false)?)),
+                None => Ok(Null)
+            }
+        },
+        _ => Err(jvm.exception("java/lang/NullPointerException", Some("key can't be null")))
     }
 }
 
-pub fn register_natives(c: &crate::Class, _: Vec<Types>) -> Types{
-    for f in c.fields.clone(){
-        println!("{}", f.name)
+pub fn fill_in_stack_trace(jvm: &mut Jvm, _: &Class, args: Vec<Types>) -> Result<Types, Thrown>{
+    if let Ref(throwable) = args[0]{
+        jvm.fill_in_stack_trace(throwable)?;
     }
-    Void
-}
\ No newline at end of file
+    return Ok(args[0].clone());
+}
+
+/// Returns a copy of the recorded stack trace, so callers can't alter it.
+pub fn get_stack_trace(jvm: &mut Jvm, c: &Class, args: Vec<Types>) -> Result<Types, Thrown>{
+    let Ref(throwable) = args[0] else { panic!("Expected a Throwable, got {:?}", args[0]) };
+    let slot = c.field_slot("stackTrace", "[Ljava/lang/StackTraceElement;").expect("Throwable has no stackTrace field");
+    let elements = match &jvm.heap.get(throwable).fields[slot] {
+        Array(array) => jvm.heap.array(*array).data.clone(),
+        _ => Vec::new()
+    };
+    let copy = jvm.alloc_array(ElementType::Class("java/lang/StackTraceElement".to_string()), elements.len())?;
+    jvm.heap.array_mut(copy).data = elements;
+    return Ok(Array(copy));
+}
+
+/// Unwinds every Java frame; the embedder decides what exiting means.
+pub fn exit(_: &mut Jvm, _: &Class, args: Vec<Types>) -> Result<Types, Thrown>{
+    if let Int(status) = args[0]{
+        return Err(Thrown::Exit(status));
+    }
+    panic!("Expected an int exit status");
+}
@@ -1,8 +1,8 @@
-#![allow(non_camel_case_types, dead_code)]
+#![allow(non_camel_case_types, dead_code, clippy::upper_case_acronyms)]
 
 use num_derive::FromPrimitive;
 
-#[derive(FromPrimitive, Debug, Clone, Copy)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcodes{
     NOP = 0,
     ACONST_NULL = 1,
@@ -121,6 +121,7 @@ pub enum Opcodes{
     FREM = 114,
     DREM = 115,
     INEG = 116,
+    LNEG = 117,
     FNEG = 118,
     DNEG = 119,
     ISHL = 120,
@@ -0,0 +1,444 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fmt;
+use crate::Class;
+use crate::mutf8;
+use crate::types::{Attribute, Code, Const, ConstPool, ExceptionHandler, Field, FieldAccessFlags, LineNumber, MethodAccessFlags};
+
+/// Highest class file major version the loader accepts (Java 21).
+pub const MAX_MAJOR_VERSION: u16 = 65;
+
+/// Reasons a class file can be rejected while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClassFormatError{
+    BadMagic(u32),
+    UnsupportedVersion(u16, u16),
+    Truncated,
+    BadConstantIndex(u16),
+    BadConstantTag(u8),
+    BadUtf8,
+    /// The field or method with this name has a contradictory combination of access flags.
+    BadFlags(String, u16)
+}
+
+impl ClassFormatError{
+    /// The Java exception class this error is reported as.
+    pub fn java_class(&self) -> &'static str{
+        return match self {
+            ClassFormatError::UnsupportedVersion(_, _) => "java/lang/UnsupportedClassVersionError",
+            _ => "java/lang/ClassFormatError"
+        }
+    }
+}
+
+impl fmt::Display for ClassFormatError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        return match self {
+            ClassFormatError::BadMagic(magic) => write!(f, "Incompatible magic value {:#x}", magic),
+            ClassFormatError::UnsupportedVersion(major, minor) =>
+                write!(f, "Unsupported class file version {}.{} (only up to {}.0 is supported)", major, minor, MAX_MAJOR_VERSION),
+            ClassFormatError::Truncated => write!(f, "Truncated class file"),
+            ClassFormatError::BadConstantIndex(idx) => write!(f, "Invalid constant pool index {}", idx),
+            ClassFormatError::BadConstantTag(tag) => write!(f, "Invalid constant pool tag {}", tag),
+            ClassFormatError::BadUtf8 => write!(f, "Illegal UTF8 string in constant pool"),
+            ClassFormatError::BadFlags(name, flags) => write!(f, "Illegal modifiers {:#06x} for {}", flags, name)
+        }
+    }
+}
+
+/// Parses a class file held in memory. Parsing never touches the loader, so
+/// classes can come from any source and nested loads don't interfere.
+pub struct ClassParser<'a>{
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> ClassParser<'a>{
+    pub fn new(data: &'a [u8]) -> Self{
+        ClassParser{data, pos: 0}
+    }
+
+    /// Consumes the next `n` bytes of the stream.
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ClassFormatError>{
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.data.len()).ok_or(ClassFormatError::Truncated)?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        return Ok(slice);
+    }
+
+    pub fn u1(&mut self) -> Result<u8, ClassFormatError>{
+        return Ok(self.bytes::<1>()?[0]);
+    }
+
+    pub fn u2(&mut self) -> Result<u16, ClassFormatError>{
+        return Ok(u16::from_be_bytes(self.bytes()?));
+    }
+
+    pub fn u4(&mut self) -> Result<u32, ClassFormatError>{
+        return Ok(u32::from_be_bytes(self.bytes()?));
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> Result<[u8; N], ClassFormatError>{
+        let mut buf = [0u8; N];
+        buf.copy_from_slice(self.take(N)?);
+        return Ok(buf);
+    }
+
+    pub fn vec_bytes(&mut self, n: usize) -> Result<Vec<u8>, ClassFormatError>{
+        return Ok(self.take(n)?.to_vec());
+    }
+
+    pub fn vec_bytes_u4(&mut self) -> Result<Vec<u8>, ClassFormatError>{
+        let num = self.u4()? as usize;
+        return self.vec_bytes(num);
+    }
+
+    pub fn vec_bytes_u2(&mut self) -> Result<Vec<u8>, ClassFormatError>{
+        let num = self.u2()? as usize;
+        return self.vec_bytes(num);
+    }
+
+    /// Like `Loader::resolve`, but rejects indices that are out of range or don't name a string.
+    fn resolve_checked(cp: &ConstPool, i: u16) -> Result<String, ClassFormatError>{
+        return match cp.consts.get((i as usize).wrapping_sub(1)) {
+            Some(Const::Str(s, _)) => Ok(s.clone()),
+            // `cpinfo` checked that it names a string.
+            Some(Const::Class(idx)) => Self::resolve_checked(cp, *idx),
+            _ => Err(ClassFormatError::BadConstantIndex(i))
+        }
+    }
+
+    fn resolve_u2(&mut self, cp: &mut ConstPool) -> Result<String, ClassFormatError>{
+        let idx = self.u2()?;
+        return Self::resolve_checked(cp, idx);
+    }
+
+    fn resolve_super(&mut self, cp: &mut ConstPool) -> Result<String, ClassFormatError>{
+        let idx = self.u2()?;
+        return if idx == 0 {
+            Ok("".to_string())
+        } else {
+            Self::resolve_checked(cp, idx)
+        }
+    }
+
+    fn cpinfo(&mut self) -> Result<ConstPool, ClassFormatError>{
+        let mut cp = ConstPool{consts: Vec::new()};
+        let cp_count = self.u2()?;
+        trace!("Constants: {}", cp_count);
+        let mut long_or_double = false;
+        for _ in 1..cp_count {
+            if long_or_double {
+                long_or_double = false;
+                cp.consts.push(Const::Invalid);
+                continue;
+            }
+            let tag = self.u1()?;
+            let c: Const = match tag{
+                0x01 => {
+                    let units = mutf8::decode_utf16(&self.vec_bytes_u2()?).ok_or(ClassFormatError::BadUtf8)?;
+                    Const::Str(String::from_utf16_lossy(&units), units.into())
+                },
+                0x03 => Const::Int(i32::from_be_bytes(self.bytes()?)),
+                0x04 => Const::Float(f32::from_be_bytes(self.bytes()?)),
+                0x05 => { long_or_double = true; Const::Long(i64::from_be_bytes(self.bytes()?))},
+                0x06 => { long_or_double = true; Const::Double(f64::from_be_bytes(self.bytes()?))},
+                0x07 => Const::Class(self.u2()?),
+                0x08 => Const::StrIndex(self.u2()?),
+                0x09|0xa => Const::FMIRef((self.u2()?, self.u2()?)),
+                0x0b => Const::InterfaceMethodRef((self.u2()?, self.u2()?)),
+                0x0c => Const::NameAndType((self.u2()?, self.u2()?)),
+                0x0f => Const::MethodHandle((self.u1()?, self.u2()?)),
+                0x10 => Const::MethodType(self.u2()?),
+                0x11 => Const::Dynamic((self.u2()?, self.u2()?)),
+                0x12 => Const::InvokeDynamic((self.u2()?, self.u2()?)),
+                0x13 => Const::Module(self.u2()?),
+                0x14 => Const::Package(self.u2()?),
+                tag => return Err(ClassFormatError::BadConstantTag(tag))
+            };
+            cp.consts.push(c);
+
+        }
+        // A class names itself with a string, not with another constant, so resolving it can't loop.
+        for c in &cp.consts{
+            if let Const::Class(idx) = c{
+                if !matches!(cp.consts.get((*idx as usize).wrapping_sub(1)), Some(Const::Str(_, _))){
+                    return Err(ClassFormatError::BadConstantIndex(*idx));
+                }
+            }
+        }
+        return Ok(cp);
+    }
+
+    fn interfaces(&mut self, cp: &mut ConstPool) -> Result<Vec<String>, ClassFormatError>{
+        let mut v: Vec<String> = Vec::new();
+        let interface_count = self.u2()?;
+        for _ in 0..interface_count{
+            v.push(self.resolve_u2(cp)?);
+        }
+        return Ok(v);
+    }
+
+    /// Checks the access flags of a field, or of a method, against JVMS 4.5 and 4.6.
+    fn check_flags(name: &str, flags: u16, method: bool) -> Result<(), ClassFormatError>{
+        let valid = if method {
+            let f = MethodAccessFlags::new(flags);
+            f.PUBLIC as u8 + f.PRIVATE as u8 + f.PROTECTED as u8 <= 1
+                && !(f.ABSTRACT && (f.PRIVATE || f.STATIC || f.FINAL || f.SYNCHRONIZED || f.NATIVE))
+        }else{
+            let f = FieldAccessFlags::new(flags);
+            f.PUBLIC as u8 + f.PRIVATE as u8 + f.PROTECTED as u8 <= 1 && !(f.FINAL && f.VOLATILE)
+        };
+        return if valid { Ok(()) } else { Err(ClassFormatError::BadFlags(name.to_string(), flags)) };
+    }
+
+    /// Parses the fields, or the methods, of the class.
+    fn fields(&mut self, cp: &mut ConstPool, method: bool) -> Result<Vec<Field>, ClassFormatError>{
+        let mut v: Vec<Field> = Vec::new();
+        let field_count = self.u2()?;
+        for _ in 0..field_count{
+            let flags = self.u2()?;
+            let name = self.resolve_u2(cp)?;
+            let desc = self.resolve_u2(cp)?;
+            Self::check_flags(&name, flags, method)?;
+            let attr = self.attributes(cp)?;
+            let code = match attr.iter().find(|a| a.name == "Code") {
+                Some(a) => Some(Rc::new(ClassParser::new(&a.data).code(cp)?)),
+                None => None
+            };
+            v.push(Field{flags, name, desc, attr, value: RefCell::new(None), code});
+        }
+        return Ok(v);
+    }
+
+    fn attributes(&mut self, cp: &mut ConstPool) -> Result<Vec<Attribute>, ClassFormatError>{
+        let mut v: Vec<Attribute> = Vec::new();
+        let attr_count = self.u2()?;
+
+        for _ in 0..attr_count{
+            v.push(Attribute{
+                name: self.resolve_u2(cp)?,
+                data: self.vec_bytes_u4()?
+            })
+        }
+        return Ok(v);
+    }
+
+    /// Parses the contents of a `Code` attribute.
+    fn code(&mut self, cp: &mut ConstPool) -> Result<Code, ClassFormatError>{
+        let max_stack = self.u2()?;
+        let max_locals = self.u2()?;
+        let bytecode = self.vec_bytes_u4()?;
+        let mut exception_table = Vec::new();
+        let handler_count = self.u2()?;
+        for _ in 0..handler_count{
+            let start_pc = self.u2()?;
+            let end_pc = self.u2()?;
+            let handler_pc = self.u2()?;
+            let catch_type = match self.u2()? {
+                0 => None,
+                idx => Some(Self::resolve_checked(cp, idx)?)
+            };
+            exception_table.push(ExceptionHandler{start_pc, end_pc, handler_pc, catch_type});
+        }
+        let attributes = self.attributes(cp)?;
+        let mut line_numbers = Vec::new();
+        for a in attributes.iter().filter(|a| a.name == "LineNumberTable"){
+            line_numbers.append(&mut ClassParser::new(&a.data).line_numbers()?);
+        }
+        return Ok(Code{max_stack, max_locals, bytecode, exception_table, line_numbers, attributes});
+    }
+
+    /// Parses the contents of a `LineNumberTable` attribute.
+    fn line_numbers(&mut self) -> Result<Vec<LineNumber>, ClassFormatError>{
+        let mut v = Vec::new();
+        let count = self.u2()?;
+        for _ in 0..count{
+            v.push(LineNumber{start_pc: self.u2()?, line: self.u2()?});
+        }
+        return Ok(v);
+    }
+
+    /// Parses the whole class file. The returned class has not been linked yet:
+    /// its field layout is left empty for the loader to fill in.
+    pub fn parse(&mut self) -> Result<Class, ClassFormatError>{
+        let magic = self.u4()?;
+        if magic != 0xcafebabe{
+            return Err(ClassFormatError::BadMagic(magic));
+        }
+        let version = [self.u2()?, self.u2()?];
+        trace!("Java version: {:?}", version);
+        if version[1] > MAX_MAJOR_VERSION{
+            return Err(ClassFormatError::UnsupportedVersion(version[1], version[0]));
+        }
+
+        let mut cp = self.cpinfo()?;
+        let flags = self.u2()?;
+        let name = self.resolve_u2(&mut cp)?;
+        let supr = self.resolve_super(&mut cp)?;
+        let interfaces = self.interfaces(&mut cp)?;
+        let fields = self.fields(&mut cp, false)?;
+        let methods = self.fields(&mut cp, true)?;
+        let attributes = self.attributes(&mut cp)?;
+        let source_file = match attributes.iter().find(|a| a.name == "SourceFile") {
+            Some(a) => Some(Self::resolve_checked(&cp, ClassParser::new(&a.data).u2()?)?),
+            None => None
+        };
+
+        return Ok(Class{
+            cp,
+            flags,
+            name,
+            supr,
+            interfaces,
+            fields,
+            methods,
+            attributes,
+            source_file,
+            version,
+            layout: Vec::new()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn utf8(s: &str) -> Vec<u8>{
+        let mut v = vec![0x01];
+        v.extend((s.len() as u16).to_be_bytes());
+        v.extend(s.as_bytes());
+        return v;
+    }
+
+    fn class(name_index: u16) -> Vec<u8>{
+        let mut v = vec![0x07];
+        v.extend(name_index.to_be_bytes());
+        return v;
+    }
+
+    /// The constant pool of `Test extends java/lang/Object`: the class is #2, its superclass #4.
+    fn pool() -> Vec<Vec<u8>>{
+        return vec![utf8("Test"), class(1), utf8("java/lang/Object"), class(3)];
+    }
+
+    /// Assembles a class file out of its version, constant pool and everything that follows it.
+    fn class_file(major: u16, pool: &[Vec<u8>], rest: &[u8]) -> Vec<u8>{
+        let mut v = 0xcafebabe_u32.to_be_bytes().to_vec();
+        v.extend(0u16.to_be_bytes());
+        v.extend(major.to_be_bytes());
+        v.extend((pool.len() as u16 + 1).to_be_bytes());
+        for c in pool{
+            v.extend(c);
+        }
+        v.extend(rest);
+        return v;
+    }
+
+    /// Flags, this class, superclass and empty interface, field, method and attribute tables.
+    const EMPTY_CLASS: &[u8] = &[0x00, 0x21, 0x00, 0x02, 0x00, 0x04, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn parses_empty_class(){
+        let c = ClassParser::new(&class_file(52, &pool(), EMPTY_CLASS)).parse().unwrap();
+        assert_eq!(c.name, "Test");
+        assert_eq!(c.supr, "java/lang/Object");
+        assert_eq!(c.version, [0, 52]);
+        assert!(c.fields.is_empty() && c.methods.is_empty());
+    }
+
+    #[test]
+    fn class_naming_a_class(){
+        // #1 and #2 name each other.
+        let data = class_file(52, &[class(2), class(1)], &[0x00, 0x21, 0x00, 0x01, 0x00, 0x02, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ClassParser::new(&data).parse().err(), Some(ClassFormatError::BadConstantIndex(2)));
+    }
+
+    /// A class with a single field, or method, `x` of type `I` and the given flags.
+    fn class_with_member(flags: u16, method: bool) -> Vec<u8>{
+        let mut pool = pool();
+        pool.push(utf8("x"));
+        pool.push(utf8("I"));
+        let mut rest = EMPTY_CLASS[..8].to_vec();
+        if method{
+            rest.extend([0, 0]);
+        }
+        rest.extend([0, 1]);
+        rest.extend(flags.to_be_bytes());
+        rest.extend([0, 5, 0, 6, 0, 0]);
+        if !method{
+            rest.extend([0, 0]);
+        }
+        rest.extend([0, 0]);
+        return class_file(52, &pool, &rest);
+    }
+
+    fn parse(data: &[u8]) -> Result<Class, ClassFormatError>{
+        return ClassParser::new(data).parse();
+    }
+
+    #[test]
+    fn bad_magic(){
+        let mut data = class_file(52, &pool(), EMPTY_CLASS);
+        data[..4].copy_from_slice(&0xcafed00d_u32.to_be_bytes());
+        assert_eq!(parse(&data).err(), Some(ClassFormatError::BadMagic(0xcafed00d)));
+        assert_eq!(parse(&[]).err(), Some(ClassFormatError::Truncated));
+    }
+
+    #[test]
+    fn unsupported_version(){
+        assert!(parse(&class_file(MAX_MAJOR_VERSION, &pool(), EMPTY_CLASS)).is_ok());
+        let e = parse(&class_file(MAX_MAJOR_VERSION + 1, &pool(), EMPTY_CLASS)).err().unwrap();
+        assert_eq!(e, ClassFormatError::UnsupportedVersion(MAX_MAJOR_VERSION + 1, 0));
+        assert_eq!(e.java_class(), "java/lang/UnsupportedClassVersionError");
+    }
+
+    #[test]
+    fn truncated(){
+        let data = class_with_member(0x0001, true);
+        for len in 0..data.len(){
+            assert_eq!(parse(&data[..len]).err(), Some(ClassFormatError::Truncated), "length {}", len);
+        }
+        assert!(parse(&data).is_ok());
+    }
+
+    #[test]
+    fn bad_constant_index(){
+        let mut rest = EMPTY_CLASS.to_vec();
+        // Superclass past the end of the pool, then the zero index.
+        rest[5] = 9;
+        assert_eq!(parse(&class_file(52, &pool(), &rest)).err(), Some(ClassFormatError::BadConstantIndex(9)));
+        rest[2..4].copy_from_slice(&[0, 0]);
+        assert_eq!(parse(&class_file(52, &pool(), &rest)).err(), Some(ClassFormatError::BadConstantIndex(0)));
+        // A class naming an integer.
+        let data = class_file(52, &[vec![0x03, 0, 0, 0, 1], class(1)], EMPTY_CLASS);
+        assert_eq!(parse(&data).err(), Some(ClassFormatError::BadConstantIndex(1)));
+    }
+
+    #[test]
+    fn bad_constant_tag(){
+        let mut pool = pool();
+        pool.push(vec![0x02]);
+        assert_eq!(parse(&class_file(52, &pool, EMPTY_CLASS)).err(), Some(ClassFormatError::BadConstantTag(2)));
+    }
+
+    #[test]
+    fn bad_utf8(){
+        let mut pool = pool();
+        pool.push(vec![0x01, 0, 2, 0xc0, 0x41]);
+        assert_eq!(parse(&class_file(52, &pool, EMPTY_CLASS)).err(), Some(ClassFormatError::BadUtf8));
+    }
+
+    #[test]
+    fn bad_flags(){
+        assert!(parse(&class_with_member(0x0019, false)).is_ok());
+        assert!(parse(&class_with_member(0x0409, true)).is_err());
+        for (flags, method) in [(0x0003, false), (0x0005, false), (0x0050, false), (0x0006, true), (0x0408, true), (0x0402, true), (0x0500, true)]{
+            let e = parse(&class_with_member(flags, method)).err();
+            assert_eq!(e, Some(ClassFormatError::BadFlags("x".to_string(), flags)));
+            assert_eq!(e.unwrap().java_class(), "java/lang/ClassFormatError");
+        }
+        assert!(parse(&class_with_member(0x0401, true)).is_ok());
+    }
+}
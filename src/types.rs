@@ -1,5 +1,8 @@
+use std::cell::RefCell;
+use std::rc::Rc;
 use num_derive::FromPrimitive;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(FromPrimitive, Copy, Clone, Debug)]
 pub enum ArrayTypes{
     BOOLEAN = 4,
@@ -18,7 +21,49 @@ pub struct Field{
     pub name: String,
     pub desc: String,
     pub attr: Vec<Attribute>,
-    pub value: Option<Types>
+    /// Current value of a static field.
+    pub value: RefCell<Option<Types>>,
+    /// The parsed `Code` attribute of a non-abstract, non-native method.
+    pub code: Option<Rc<Code>>
+}
+
+/// The body of a method.
+#[derive(Debug, Clone, Default)]
+pub struct Code{
+    pub max_stack: u16,
+    pub max_locals: u16,
+    pub bytecode: Vec<u8>,
+    pub exception_table: Vec<ExceptionHandler>,
+    /// Entries of the `LineNumberTable` attributes, if the class was compiled with them.
+    pub line_numbers: Vec<LineNumber>,
+    pub attributes: Vec<Attribute>
+}
+
+impl Code{
+    /// Returns the source line of the instruction at `pc`, if known.
+    pub fn line_number(&self, pc: u32) -> Option<u16>{
+        return self.line_numbers.iter()
+            .filter(|l| l.start_pc as u32 <= pc)
+            .max_by_key(|l| l.start_pc)
+            .map(|l| l.line);
+    }
+}
+
+/// A `LineNumberTable` entry: the instructions from `start_pc` on come from source line `line`.
+#[derive(Debug, Clone)]
+pub struct LineNumber{
+    pub start_pc: u16,
+    pub line: u16
+}
+
+/// An exception table entry: exceptions thrown by the instructions in `start_pc..end_pc`
+/// that are instances of `catch_type` (of any class, for `None`) are handled at `handler_pc`.
+#[derive(Debug, Clone)]
+pub struct ExceptionHandler{
+    pub start_pc: u16,
+    pub end_pc: u16,
+    pub handler_pc: u16,
+    pub catch_type: Option<String>
 }
 
 #[derive(Debug, Clone)]
@@ -29,15 +74,27 @@ pub struct Attribute{
 
 #[derive(Debug, Clone)]
 pub enum Const{
-    Str(String),
+    /// A `CONSTANT_Utf8`: its text, and its exact UTF-16 code units. Only the latter keep
+    /// unpaired surrogates, which Java strings may contain but Rust strings cannot.
+    Str(String, Rc<[u16]>),
     Int(i32),
     Float(f32),
     Double(f64),
     Long(i64),
     Class(u16),
     FMIRef((u16, u16)),
+    InterfaceMethodRef((u16, u16)),
     StrIndex(u16),
     NameAndType((u16, u16)),
+    /// Reference kind and index of the referenced field or method.
+    MethodHandle((u8, u16)),
+    /// Index of the method descriptor.
+    MethodType(u16),
+    /// Bootstrap method attribute index and name-and-type index.
+    Dynamic((u16, u16)),
+    InvokeDynamic((u16, u16)),
+    Module(u16),
+    Package(u16),
     Invalid
 }
 
@@ -49,9 +106,51 @@ pub enum Types{
     Long(i64),
     Void,
     Class(String),
-    Array((usize, ArrayTypes)),
+    Array(usize),
     Boolean(bool),
-    Str(String)
+    Str(String),
+    Ref(usize),
+    Null
+}
+
+impl Types{
+    /// Whether two references point to the same object, as compared by `IF_ACMPEQ`.
+    pub fn same_ref(&self, other: &Types) -> bool{
+        return match (self, other) {
+            (Types::Ref(a), Types::Ref(b)) | (Types::Array(a), Types::Array(b)) => a == b,
+            (Types::Class(a), Types::Class(b)) | (Types::Str(a), Types::Str(b)) => a == b,
+            (Types::Null, Types::Null) => true,
+            _ => false
+        }
+    }
+
+    /// Computational type category (JVMS 2.11.1): 2 for `long` and `double`, which take two
+    /// local variable slots and count as two operand stack words, 1 for everything else.
+    pub fn category(&self) -> usize{
+        return match self {
+            Types::Long(_) | Types::Double(_) => 2,
+            _ => 1
+        }
+    }
+
+    /// Returns the default value of a field with the given descriptor.
+    pub fn default_for(desc: &str) -> Types{
+        return match desc.chars().next() {
+            Some('J') => Types::Long(0),
+            Some('F') => Types::Float(0.0),
+            Some('D') => Types::Double(0.0),
+            Some('L') | Some('[') => Types::Null,
+            _ => Types::Int(0)
+        }
+    }
+}
+
+/// An instance field slot: `class` is the class declaring the field.
+#[derive(Debug, Clone)]
+pub struct FieldSlot{
+    pub class: String,
+    pub name: String,
+    pub desc: String
 }
 
 #[derive(Debug, Clone)]
@@ -60,9 +159,67 @@ pub struct ConstPool{
 }
 
 impl ConstPool{
+    /// Returns constant `idx`, or `Const::Invalid` if the pool has no such entry.
     pub fn get(&self, idx:u16) -> Const{
-        let idx = (idx-1) as usize;
-        return self.consts[idx].clone();
+        return self.consts.get((idx as usize).wrapping_sub(1)).cloned().unwrap_or(Const::Invalid);
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct ClassAccessFlags{
+    pub PUBLIC: bool,
+    pub FINAL: bool,
+    pub SUPER: bool,
+    pub INTERFACE: bool,
+    pub ABSTRACT: bool,
+    pub SYNTHETIC: bool,
+    pub ANNOTATION: bool,
+    pub ENUM: bool,
+    pub MODULE: bool
+}
+
+impl ClassAccessFlags{
+    pub fn new(flags: u16) -> Self{
+        Self{
+            PUBLIC: flags & 0x1 != 0,
+            FINAL: flags & 0x10 != 0,
+            SUPER: flags & 0x20 != 0,
+            INTERFACE: flags & 0x200 != 0,
+            ABSTRACT: flags & 0x400 != 0,
+            SYNTHETIC: flags & 0x1000 != 0,
+            ANNOTATION: flags & 0x2000 != 0,
+            ENUM: flags & 0x4000 != 0,
+            MODULE: flags & 0x8000 != 0
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct FieldAccessFlags{
+    pub PUBLIC: bool,
+    pub PRIVATE: bool,
+    pub PROTECTED: bool,
+    pub STATIC: bool,
+    pub FINAL: bool,
+    pub VOLATILE: bool,
+    pub TRANSIENT: bool,
+    pub SYNTHETIC: bool,
+    pub ENUM: bool
+}
+
+impl FieldAccessFlags{
+    pub fn new(flags: u16) -> Self{
+        Self{
+            PUBLIC: flags & 0x1 != 0,
+            PRIVATE: flags & 0x2 != 0,
+            PROTECTED: flags & 0x4 != 0,
+            STATIC: flags & 0x8 != 0,
+            FINAL: flags & 0x10 != 0,
+            VOLATILE: flags & 0x40 != 0,
+            TRANSIENT: flags & 0x80 != 0,
+            SYNTHETIC: flags & 0x1000 != 0,
+            ENUM: flags & 0x4000 != 0
+        }
     }
 }
 
@@ -84,7 +241,7 @@ pub struct MethodAccessFlags{
 
 impl MethodAccessFlags{
     pub fn new(flags: u16) -> Self{
-        let f = Self{
+        Self{
             PUBLIC: flags & 0x1 != 0,
             PRIVATE: flags & 0x2 != 0,
             PROTECTED: flags & 0x4 != 0,
@@ -97,12 +254,6 @@ impl MethodAccessFlags{
             ABSTRACT: flags & 0x400 != 0,
             STRICT: flags & 0x800 != 0,
             SYNTHETIC: flags & 0x1000 != 0
-        };
-
-        if f.PUBLIC && f.PRIVATE || f.PUBLIC && f.PROTECTED || f.PRIVATE && f.PROTECTED{
-            panic!("Invalid flags.");
         }
-
-        f
     }
 }
\ No newline at end of file